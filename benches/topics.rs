@@ -0,0 +1,44 @@
+//! Times conversions of 1 to 12 camera topics with the same number of frames
+//! each. With a thread per topic, N topics should take about as long as one
+//! topic until N exceeds the cores; run with `cargo bench --bench topics`.
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use mcap_videoify::ConvertOptions;
+use std::time::{Duration, Instant};
+
+const FRAMES: usize = 40;
+
+fn time(input: &[u8], opts: &ConvertOptions) -> Duration {
+    (0..3)
+        .map(|_| {
+            let start = Instant::now();
+            common::run(input, opts);
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn main() {
+    let opts = ConvertOptions::default();
+    let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+    println!("{} frames of 320x240 per topic, {} cores", FRAMES, cores);
+    println!("{:>6} {:>10} {:>12} {:>8}", "topics", "time", "per frame", "speedup");
+    let mut single = None;
+    for topics in [1, 2, 4, 8, 12] {
+        let input = common::cameras_mcap(topics, FRAMES, 320, 240);
+        let elapsed = time(&input, &opts);
+        let single = *single.get_or_insert(elapsed);
+        // Against converting the topics one after another at the one-topic speed.
+        let speedup = single.as_secs_f64() * topics as f64 / elapsed.as_secs_f64();
+        println!(
+            "{:>6} {:>8.0}ms {:>10.2}ms {:>7.2}x",
+            topics,
+            elapsed.as_secs_f64() * 1000.0,
+            elapsed.as_secs_f64() * 1000.0 / (topics * FRAMES) as f64,
+            speedup
+        );
+    }
+}
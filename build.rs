@@ -5,6 +5,7 @@ fn main() {
         .pure()
         .cargo_out_dir("generated_protos")
         .include("src/protos")
+        .input("src/protos/CompressedImage.proto")
         .input("src/protos/CompressedVideo.proto")
         .run_from_script();
 }
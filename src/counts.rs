@@ -0,0 +1,174 @@
+//! Per-topic message counts and time ranges, read from the summary section when
+//! the input has one so planning doesn't require decoding every chunk.
+
+use anyhow::Result;
+use std::collections::BTreeMap;
+
+/// Message count and `log_time` range of one topic.
+#[derive(Clone)]
+pub struct TopicCount {
+    /// Schema name, or `None` for schemaless channels.
+    pub schema: Option<String>,
+    /// Schema encoding, or `None` for schemaless channels.
+    pub schema_encoding: Option<String>,
+    pub message_count: u64,
+    /// Earliest and latest `log_time`, or `None` when the topic has no messages.
+    pub time_range: Option<(u64, u64)>,
+}
+
+impl TopicCount {
+    fn record(&mut self, log_time: u64) {
+        self.message_count += 1;
+        self.time_range = Some(match self.time_range {
+            Some((start, end)) => (start.min(log_time), end.max(log_time)),
+            None => (log_time, log_time),
+        });
+    }
+}
+
+/// Counts for every topic of an input, keyed by topic name.
+pub struct TopicCounts {
+    pub topics: BTreeMap<String, TopicCount>,
+    /// Whether the counts came from the summary's message indexes rather than a full scan.
+    pub from_summary: bool,
+}
+
+impl TopicCounts {
+    /// Reads counts from the summary's chunk message indexes, which only touches
+    /// the index records, falling back to scanning every message when the input
+    /// has no summary or its chunks aren't indexed.
+    pub fn read(mapped: &[u8]) -> Result<Self> {
+        if let Some(topics) = from_summary(mapped) {
+            return Ok(Self { topics, from_summary: true });
+        }
+
+        let mut topics: BTreeMap<String, TopicCount> = BTreeMap::new();
+        for message in mcap::MessageStream::new(mapped)? {
+            let message = message?;
+            topic_entry(&mut topics, &message.channel).record(message.log_time);
+        }
+        Ok(Self { topics, from_summary: false })
+    }
+
+    pub fn total_messages(&self) -> u64 {
+        self.topics.values().map(|t| t.message_count).sum()
+    }
+}
+
+fn topic_entry<'a>(topics: &'a mut BTreeMap<String, TopicCount>, channel: &mcap::Channel) -> &'a mut TopicCount {
+    topics.entry(channel.topic.clone()).or_insert_with(|| TopicCount {
+        schema: channel.schema.as_ref().map(|s| s.name.clone()),
+        schema_encoding: channel.schema.as_ref().map(|s| s.encoding.clone()),
+        message_count: 0,
+        time_range: None,
+    })
+}
+
+fn from_summary(mapped: &[u8]) -> Option<BTreeMap<String, TopicCount>> {
+    let summary = mcap::Summary::read(mapped).ok()??;
+    count_indexed(mapped, &summary)
+}
+
+/// Counts the messages listed by the message indexes of `summary`, or `None`
+/// unless those list every message of the file.
+fn count_indexed(mapped: &[u8], summary: &mcap::Summary) -> Option<BTreeMap<String, TopicCount>> {
+    // Files written with `--chunk-indexes off` or `--message-indexes off` have
+    // nothing to count from, and a file with only some chunks indexed would
+    // come up short. Chunks holding only schema and channel records have no
+    // message index and no message time range.
+    let unindexed = |c: &&mcap::records::ChunkIndex| c.message_index_offsets.is_empty();
+    if summary.chunk_indexes.is_empty()
+        || summary
+            .chunk_indexes
+            .iter()
+            .filter(unindexed)
+            .any(|c| (c.message_start_time, c.message_end_time) != (0, 0))
+    {
+        return None;
+    }
+
+    let mut topics = BTreeMap::new();
+    for channel in summary.channels.values() {
+        topic_entry(&mut topics, channel);
+    }
+    for chunk_index in summary.chunk_indexes.iter().filter(|c| !unindexed(c)) {
+        let indexes = summary.read_message_indexes(mapped, chunk_index).ok()?;
+        for (channel, entries) in indexes {
+            let topic = topic_entry(&mut topics, &channel);
+            for entry in entries {
+                topic.record(entry.log_time);
+            }
+        }
+    }
+    // Messages at log_time 0 look like an empty chunk; the statistics catch those.
+    let counted: u64 = topics.values().map(|t| t.message_count).sum();
+    if summary.stats.as_ref().is_some_and(|stats| stats.message_count != counted) {
+        return None;
+    }
+    Some(topics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::borrow::Cow;
+    use std::io::Cursor;
+    use std::sync::Arc;
+
+    /// Sixty messages on two topics, over several chunks, from log_time `start`.
+    fn chunked_mcap(start: u64) -> Vec<u8> {
+        let mut file = Cursor::new(Vec::new());
+        let mut out = mcap::WriteOptions::new().chunk_size(Some(500)).create(&mut file).unwrap();
+        let channels: Vec<_> = ["/a", "/b"]
+            .iter()
+            .map(|topic| {
+                Arc::new(mcap::Channel {
+                    topic: topic.to_string(),
+                    schema: None,
+                    message_encoding: "json".to_string(),
+                    metadata: BTreeMap::new(),
+                })
+            })
+            .collect();
+        for i in 0..60u64 {
+            out.write(&mcap::Message {
+                channel: channels[i as usize % 2].clone(),
+                sequence: i as u32,
+                log_time: start + i,
+                publish_time: start + i,
+                data: Cow::from(format!("{{\"i\":{}}}", i).into_bytes()),
+            })
+            .unwrap();
+        }
+        out.finish().unwrap();
+        drop(out);
+        file.into_inner()
+    }
+
+    #[test]
+    fn fully_indexed_files_are_counted_from_the_summary() {
+        let mcap = chunked_mcap(1000);
+        let topics = from_summary(&mcap).unwrap();
+        assert_eq!(topics["/a"].message_count, 30);
+        assert_eq!(topics["/b"].time_range, Some((1001, 1059)));
+    }
+
+    #[test]
+    fn partly_indexed_files_are_scanned() {
+        let mcap = chunked_mcap(1000);
+        let mut summary = mcap::Summary::read(&mcap).unwrap().unwrap();
+        assert!(summary.chunk_indexes.len() > 2);
+        summary.chunk_indexes[1].message_index_offsets.clear();
+        assert!(count_indexed(&mcap, &summary).is_none());
+    }
+
+    #[test]
+    fn unindexed_chunks_at_time_zero_are_caught_by_the_statistics() {
+        let mcap = chunked_mcap(0);
+        let mut summary = mcap::Summary::read(&mcap).unwrap().unwrap();
+        // The first chunk starts at log_time 0; pretend it also ends there.
+        summary.chunk_indexes[0].message_end_time = 0;
+        summary.chunk_indexes[0].message_index_offsets.clear();
+        assert!(count_indexed(&mcap, &summary).is_none());
+    }
+}
@@ -0,0 +1,453 @@
+//! Pixel-level processing applied to decoded frames before they are encoded.
+
+use anyhow::Result;
+use image::codecs::gif::GifDecoder;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngDecoder;
+use image::imageops::{self, FilterType};
+use image::{AnimationDecoder, ColorType, DynamicImage, Frame, ImageFormat, Rgb, RgbImage};
+use std::fmt;
+use std::io::Cursor;
+
+/// Hard per-dimension size caps set by `--max-width` / `--max-height`.
+#[derive(Clone, Copy, Default)]
+pub struct SizeLimit {
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    /// Stretch to the capped size instead of letterboxing to keep the aspect ratio.
+    pub allow_stretch: bool,
+}
+
+impl SizeLimit {
+    pub fn is_set(&self) -> bool {
+        self.max_width.is_some() || self.max_height.is_some()
+    }
+
+    /// Shrinks `img` so that neither dimension exceeds its cap. Frames already
+    /// within both caps are returned untouched; frames are never enlarged.
+    ///
+    /// With a single cap the other dimension follows the aspect ratio, rounded
+    /// to an even number of pixels. With both caps the result is
+    /// `min(width, max_width)` x `min(height, max_height)`: with `allow_stretch`
+    /// the picture is resized to exactly that size, otherwise it is scaled to
+    /// fit while keeping its aspect ratio and padded with black bars.
+    pub fn apply(&self, img: RgbImage) -> RgbImage {
+        let (width, height) = img.dimensions();
+        let target_width = self.max_width.map_or(width, |max| width.min(max)).max(1);
+        let target_height = self.max_height.map_or(height, |max| height.min(max)).max(1);
+        if (target_width, target_height) == (width, height) {
+            return img;
+        }
+
+        // Scales `other` by `capped / original`, to an even size no larger than `other`.
+        let follow = |other: u32, capped: u32, original: u32| {
+            let scaled = other as f64 * capped as f64 / original as f64;
+            (((scaled / 2.0).round() as u32) * 2).max(2).min(other)
+        };
+        match (self.max_width, self.max_height) {
+            (Some(_), None) => {
+                let height = follow(height, target_width, width);
+                imageops::resize(&img, target_width, height, FilterType::Triangle)
+            }
+            (None, Some(_)) => {
+                let width = follow(width, target_height, height);
+                imageops::resize(&img, width, target_height, FilterType::Triangle)
+            }
+            _ if self.allow_stretch => imageops::resize(&img, target_width, target_height, FilterType::Triangle),
+            _ => letterbox(&img, target_width, target_height),
+        }
+    }
+}
+
+/// Scales `img` up or down to fit `width` x `height` while keeping its aspect
+/// ratio, centred on black bars.
+pub fn letterbox(img: &RgbImage, width: u32, height: u32) -> RgbImage {
+    let scale = f64::min(
+        width as f64 / img.width() as f64,
+        height as f64 / img.height() as f64,
+    );
+    let fit_width = ((img.width() as f64 * scale).round() as u32).clamp(1, width);
+    let fit_height = ((img.height() as f64 * scale).round() as u32).clamp(1, height);
+    let scaled = imageops::resize(img, fit_width, fit_height, FilterType::Triangle);
+
+    let mut boxed = RgbImage::from_pixel(width, height, Rgb([0, 0, 0]));
+    imageops::replace(
+        &mut boxed,
+        &scaled,
+        ((width - fit_width) / 2) as i64,
+        ((height - fit_height) / 2) as i64,
+    );
+    boxed
+}
+
+/// Smallest picture dimension openh264 accepts.
+pub const MIN_ENCODER_DIMENSION: u32 = 16;
+
+/// Whether openh264 can encode a frame of this size: at least
+/// [`MIN_ENCODER_DIMENSION`] on each side and even, as 4:2:0 chroma requires.
+pub fn fits_encoder(img: &RgbImage) -> bool {
+    let (width, height) = img.dimensions();
+    width >= MIN_ENCODER_DIMENSION && height >= MIN_ENCODER_DIMENSION && width % 2 == 0 && height % 2 == 0
+}
+
+/// Pads `img` with black on the right and bottom up to the nearest size openh264
+/// accepts, keeping the picture at the top-left so it can be cropped back out.
+pub fn pad_for_encoder(img: &RgbImage) -> RgbImage {
+    let (width, height) = img.dimensions();
+    let padded_width = width.max(MIN_ENCODER_DIMENSION).next_multiple_of(2);
+    let padded_height = height.max(MIN_ENCODER_DIMENSION).next_multiple_of(2);
+    let mut padded = RgbImage::from_pixel(padded_width, padded_height, Rgb([0, 0, 0]));
+    imageops::replace(&mut padded, img, 0, 0);
+    padded
+}
+
+/// Size a decoder shows of a frame padded by [`pad_for_encoder`], once the SPS
+/// crops the padding off. Cropping works in 2-pixel steps in 4:2:0, so an odd
+/// side keeps one row or column of padding.
+pub fn cropped_size((width, height): (u32, u32)) -> (u32, u32) {
+    (width.next_multiple_of(2), height.next_multiple_of(2))
+}
+
+/// Container format, pixel layout and size of a decoded source image. A topic
+/// whose images switch any of them mid-stream is handled according to
+/// `--on-format-change`; an encoder only takes frames of the size it was created for.
+#[derive(Clone, Copy, PartialEq)]
+pub struct SourceFormat {
+    pub container: Option<ImageFormat>,
+    pub color: ColorType,
+    pub size: (u32, u32),
+}
+
+impl fmt::Display for SourceFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (width, height) = self.size;
+        match self.container {
+            Some(container) => write!(f, "{}x{} {:?} {:?}", width, height, container, self.color),
+            None => write!(f, "{}x{} unknown {:?}", width, height, self.color),
+        }
+    }
+}
+
+/// Tone-mapping operator set by `--tonemap` for high-bit-depth sources.
+#[derive(Clone, Copy)]
+pub enum Tonemap {
+    Reinhard,
+    /// Narkowicz's fit of the ACES filmic curve.
+    Aces,
+}
+
+/// Peak of a high-bit-depth source relative to SDR reference white: a
+/// 1000-nit peak over the 203-nit reference white of ITU-R BT.2408.
+const HDR_HEADROOM: f32 = 1000.0 / 203.0;
+
+/// Whether `color` carries more than 8 bits per channel (16-bit or float samples).
+pub fn is_high_bit_depth(color: ColorType) -> bool {
+    color.bytes_per_pixel() > color.channel_count()
+}
+
+/// Maps a high-bit-depth image to 8-bit sRGB. Samples are taken as linear light
+/// with full scale at [`HDR_HEADROOM`] times SDR white, compressed by `op` and
+/// then sRGB-encoded, instead of being truncated to 8 bits.
+pub fn tonemap(img: &DynamicImage, op: Tonemap) -> RgbImage {
+    let linear = img.to_rgb32f();
+    let mut lut = Vec::with_capacity(4096);
+    for i in 0..4096 {
+        let x = i as f32 / 4095.0 * HDR_HEADROOM;
+        let mapped = match op {
+            Tonemap::Reinhard => x / (1.0 + x),
+            Tonemap::Aces => (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14),
+        }
+        .clamp(0.0, 1.0);
+        let encoded = if mapped <= 0.003_130_8 {
+            12.92 * mapped
+        } else {
+            1.055 * mapped.powf(1.0 / 2.4) - 0.055
+        };
+        lut.push((encoded * 255.0).round() as u8);
+    }
+
+    let mut out = RgbImage::new(linear.width(), linear.height());
+    for (dst, src) in out.iter_mut().zip(linear.iter()) {
+        *dst = lut[(src.clamp(0.0, 1.0) * 4095.0).round() as usize];
+    }
+    out
+}
+
+/// Placeholder frame set by `--error-image`, encoded in place of images that fail to decode.
+pub enum ErrorImage {
+    Color(Rgb<u8>),
+    Image(RgbImage),
+}
+
+impl ErrorImage {
+    /// Parses a `#RRGGBB` colour, or otherwise loads the value as an image file.
+    pub fn parse(value: &str) -> Result<Self> {
+        if let Some(hex) = value.strip_prefix('#') {
+            let color = u32::from_str_radix(hex, 16)
+                .ok()
+                .filter(|_| hex.len() == 6)
+                .ok_or_else(|| anyhow::anyhow!("Invalid --error-image colour {}. Expected #RRGGBB", value))?;
+            let [_, r, g, b] = color.to_be_bytes();
+            return Ok(Self::Color(Rgb([r, g, b])));
+        }
+        let image = image::open(value).map_err(|e| anyhow::anyhow!("Couldn't load --error-image {}: {}", value, e))?;
+        Ok(Self::Image(image.to_rgb8()))
+    }
+
+    /// Renders the placeholder at `width` x `height`, stretching a provided image to fit.
+    pub fn render(&self, width: u32, height: u32) -> RgbImage {
+        match self {
+            Self::Color(color) => RgbImage::from_pixel(width, height, *color),
+            Self::Image(image) => imageops::resize(image, width, height, FilterType::Triangle),
+        }
+    }
+}
+
+/// Per-channel tone adjustment set by `--gamma`, `--brightness` and `--contrast`.
+#[derive(Clone, Copy)]
+pub struct ToneAdjust {
+    /// Output is `input^(1 / gamma)`, so values above 1 brighten shadows. Must be > 0.
+    pub gamma: f64,
+    /// Offset added after the other adjustments, as a fraction of full scale in [-1, 1].
+    pub brightness: f64,
+    /// Scale applied around mid-grey; 1 keeps contrast unchanged. Must be >= 0.
+    pub contrast: f64,
+}
+
+impl Default for ToneAdjust {
+    fn default() -> Self {
+        Self {
+            gamma: 1.0,
+            brightness: 0.0,
+            contrast: 1.0,
+        }
+    }
+}
+
+impl ToneAdjust {
+    pub fn is_identity(&self) -> bool {
+        self.gamma == 1.0 && self.brightness == 0.0 && self.contrast == 1.0
+    }
+
+    /// Builds the lookup table applying gamma, then contrast, then brightness.
+    pub fn lut(&self) -> [u8; 256] {
+        let mut lut = [0u8; 256];
+        for (i, entry) in lut.iter_mut().enumerate() {
+            let v = (i as f64 / 255.0).powf(1.0 / self.gamma);
+            let v = (v - 0.5) * self.contrast + 0.5 + self.brightness;
+            *entry = (v.clamp(0.0, 1.0) * 255.0).round() as u8;
+        }
+        lut
+    }
+}
+
+/// Maps every channel of every pixel through `lut`.
+pub fn apply_lut(img: &mut RgbImage, lut: &[u8; 256]) {
+    for value in img.iter_mut() {
+        *value = lut[*value as usize];
+    }
+}
+
+/// Most pixels sampled from a frame when building a palette.
+const PALETTE_SAMPLES: usize = 1 << 16;
+
+/// A fixed set of colours that frames are reduced to by `--palette`.
+///
+/// The palette is built once, by median cut over the first frame of a topic, so
+/// colours stay stable from frame to frame. Nearest-colour lookups are cached per
+/// 15-bit colour.
+pub struct Palette {
+    colors: Vec<[u8; 3]>,
+    lookup: Vec<Option<u8>>,
+}
+
+impl Palette {
+    /// Builds a palette of at most `size` colours (2-256) from `img`.
+    pub fn from_image(img: &RgbImage, size: usize) -> Self {
+        let step = (img.width() as usize * img.height() as usize / PALETTE_SAMPLES).max(1);
+        let pixels: Vec<[u8; 3]> = img.pixels().step_by(step).map(|p| p.0).collect();
+
+        // Repeatedly split the box with the widest channel range at its median.
+        let mut boxes = vec![pixels];
+        while boxes.len() < size {
+            let widest = boxes
+                .iter()
+                .enumerate()
+                .filter(|(_, b)| b.len() > 1)
+                .map(|(i, b)| {
+                    let (channel, range) = (0..3)
+                        .map(|c| {
+                            let (min, max) = b.iter().fold((255, 0), |(lo, hi), p| (p[c].min(lo), p[c].max(hi)));
+                            (c, max - min)
+                        })
+                        .max_by_key(|&(_, range)| range)
+                        .unwrap();
+                    (i, channel, range)
+                })
+                .filter(|&(_, _, range)| range > 0)
+                .max_by_key(|&(_, _, range)| range);
+            let Some((i, channel, _)) = widest else {
+                break;
+            };
+            let mut split = boxes.swap_remove(i);
+            split.sort_unstable_by_key(|p| p[channel]);
+            let upper = split.split_off(split.len() / 2);
+            boxes.push(split);
+            boxes.push(upper);
+        }
+
+        let colors = boxes
+            .iter()
+            .filter(|b| !b.is_empty())
+            .map(|b| {
+                let mut sum = [0usize; 3];
+                for p in b {
+                    for c in 0..3 {
+                        sum[c] += usize::from(p[c]);
+                    }
+                }
+                sum.map(|s| (s / b.len()) as u8)
+            })
+            .collect();
+        Self {
+            colors,
+            lookup: vec![None; 1 << 15],
+        }
+    }
+
+    fn nearest(&mut self, p: [u8; 3]) -> [u8; 3] {
+        let key = usize::from(p[0] >> 3) << 10 | usize::from(p[1] >> 3) << 5 | usize::from(p[2] >> 3);
+        let index = *self.lookup[key].get_or_insert_with(|| {
+            let distance = |c: &[u8; 3]| (0..3).map(|i| (i32::from(c[i]) - i32::from(p[i])).pow(2)).sum::<i32>();
+            (0..self.colors.len()).min_by_key(|&i| distance(&self.colors[i])).unwrap_or(0) as u8
+        });
+        self.colors[usize::from(index)]
+    }
+
+    /// Replaces every pixel of `img` with its nearest palette colour.
+    pub fn apply(&mut self, img: &mut RgbImage) {
+        for pixel in img.pixels_mut() {
+            pixel.0 = self.nearest(pixel.0);
+        }
+    }
+}
+
+/// Decodes every frame of a GIF or APNG payload, paired with each frame's
+/// offset from the first in nanoseconds. Returns `None` for other formats and
+/// for plain PNGs, which are decoded the usual way.
+pub fn decode_animation(bytes: &[u8], format: Option<ImageFormat>) -> Result<Option<Vec<(RgbImage, u64)>>> {
+    let frames: Vec<Frame> = match format {
+        Some(ImageFormat::Gif) => GifDecoder::new(Cursor::new(bytes))?.into_frames().collect_frames()?,
+        Some(ImageFormat::Png) => {
+            let decoder = PngDecoder::new(Cursor::new(bytes))?;
+            if !decoder.is_apng() {
+                return Ok(None);
+            }
+            decoder.apng().into_frames().collect_frames()?
+        }
+        _ => return Ok(None),
+    };
+
+    let mut offset_ns = 0u64;
+    let mut decoded = Vec::with_capacity(frames.len());
+    for frame in frames {
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        decoded.push((DynamicImage::ImageRgba8(frame.into_buffer()).to_rgb8(), offset_ns));
+        offset_ns += u64::from(numer) * 1_000_000 / u64::from(denom.max(1));
+    }
+    Ok(Some(decoded))
+}
+
+/// Pixel encodings of `foxglove.RawImage` that can be converted, with their
+/// bytes per pixel and the colour type reported for them.
+const RAW_ENCODINGS: &[(&str, u32, ColorType)] = &[
+    ("rgb8", 3, ColorType::Rgb8),
+    ("bgr8", 3, ColorType::Rgb8),
+    ("mono8", 1, ColorType::L8),
+];
+
+/// Whether [`decode_raw`] can convert a `foxglove.RawImage` with this pixel encoding.
+pub fn is_supported_raw_encoding(encoding: &str) -> bool {
+    RAW_ENCODINGS.iter().any(|(name, _, _)| *name == encoding)
+}
+
+/// Converts the pixel buffer of a `foxglove.RawImage` to RGB. Rows are `step`
+/// bytes apart; a `step` of 0 is taken to mean tightly packed rows.
+pub fn decode_raw(encoding: &str, width: u32, height: u32, step: u32, data: &[u8]) -> Result<(RgbImage, ColorType)> {
+    let Some(&(_, bytes_per_pixel, color)) = RAW_ENCODINGS.iter().find(|(name, _, _)| *name == encoding) else {
+        anyhow::bail!("Unsupported RawImage encoding {:?}", encoding);
+    };
+    if width == 0 || height == 0 {
+        anyhow::bail!("RawImage is {}x{}", width, height);
+    }
+    let row_len = width as usize * bytes_per_pixel as usize;
+    let step = if step == 0 { row_len } else { step as usize };
+    if step < row_len {
+        anyhow::bail!("RawImage step {} is shorter than a {}-pixel {} row", step, width, encoding);
+    }
+    let needed = step * (height as usize - 1) + row_len;
+    if data.len() < needed {
+        anyhow::bail!("RawImage data is {} bytes but a {}x{} {} image needs {}", data.len(), width, height, encoding, needed);
+    }
+
+    let mut rgb = Vec::with_capacity(width as usize * height as usize * 3);
+    for row in data.chunks(step).take(height as usize) {
+        let row = &row[..row_len];
+        match encoding {
+            "rgb8" => rgb.extend_from_slice(row),
+            "bgr8" => rgb.extend(row.chunks_exact(3).flat_map(|p| [p[2], p[1], p[0]])),
+            _ => rgb.extend(row.iter().flat_map(|&v| [v, v, v])),
+        }
+    }
+    Ok((RgbImage::from_raw(width, height, rgb).expect("buffer holds every row"), color))
+}
+
+/// Longest side of the keyframe thumbnails written by `--embed-keyframe-thumbs`.
+pub const THUMBNAIL_SIZE: u32 = 160;
+
+/// Shrinks `img` so its longer side is at most [`THUMBNAIL_SIZE`], keeping the aspect ratio.
+pub fn thumbnail(img: &RgbImage) -> RgbImage {
+    let (width, height) = img.dimensions();
+    let scale = f64::min(1.0, THUMBNAIL_SIZE as f64 / width.max(height) as f64);
+    let thumb_width = ((width as f64 * scale).round() as u32).max(1);
+    let thumb_height = ((height as f64 * scale).round() as u32).max(1);
+    imageops::thumbnail(img, thumb_width, thumb_height)
+}
+
+/// Compresses `img` as a baseline JPEG at the given quality (1-100).
+pub fn encode_jpeg(img: &RgbImage, quality: u8) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    JpegEncoder::new_with_quality(&mut data, quality).encode_image(img)?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn size_limit(max_width: Option<u32>, max_height: Option<u32>) -> SizeLimit {
+        SizeLimit { max_width, max_height, allow_stretch: false }
+    }
+
+    #[test]
+    fn single_cap_keeps_the_aspect_ratio() {
+        let img = RgbImage::new(64, 48);
+        assert_eq!(size_limit(Some(32), None).apply(img.clone()).dimensions(), (32, 24));
+        // 64 * 20 / 48 = 26.7, rounded to an even 26.
+        assert_eq!(size_limit(None, Some(20)).apply(img.clone()).dimensions(), (26, 20));
+        assert_eq!(size_limit(Some(100), None).apply(img).dimensions(), (64, 48));
+    }
+
+    #[test]
+    fn both_caps_letterbox_or_stretch() {
+        let img = RgbImage::from_pixel(64, 48, Rgb([255, 255, 255]));
+        let boxed = size_limit(Some(32), Some(48)).apply(img.clone());
+        assert_eq!(boxed.dimensions(), (32, 48));
+        assert_eq!(boxed.get_pixel(16, 0), &Rgb([0, 0, 0]));
+        assert_eq!(boxed.get_pixel(16, 24), &Rgb([255, 255, 255]));
+
+        let stretched = SizeLimit { allow_stretch: true, ..size_limit(Some(32), Some(48)) }.apply(img);
+        assert_eq!(stretched.dimensions(), (32, 48));
+        assert_eq!(stretched.get_pixel(16, 0), &Rgb([255, 255, 255]));
+    }
+}
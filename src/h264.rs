@@ -0,0 +1,467 @@
+//! H.264 encoder built directly on openh264's C API.
+//!
+//! `openh264::encoder::Encoder` initializes itself from the handful of fields
+//! `EncoderConfig` exposes, and some parameters - the usage type in particular -
+//! can only be set at initialization. This wrapper fills in the whole
+//! `SEncParamExt` block before `InitializeExt` instead.
+
+use anyhow::Result;
+use openh264::encoder::FrameType;
+use openh264::formats::YUVSource;
+use openh264_sys2::{
+    videoFormatI420, videoFrameTypeI, videoFrameTypeIDR, videoFrameTypeIPMixed, videoFrameTypeP, videoFrameTypeSkip,
+    ISVCEncoder, ISVCEncoderVtbl, SEncParamExt, SFrameBSInfo, SSourcePicture, WelsCreateSVCEncoder,
+    WelsDestroySVCEncoder, ENCODER_OPTION_DATAFORMAT, ENCODER_OPTION_SVC_ENCODE_PARAM_EXT, ENCODER_OPTION_TRACE_LEVEL,
+    RC_QUALITY_MODE, VIDEO_CODING_LAYER, WELS_LOG_QUIET,
+};
+use std::os::raw::{c_int, c_void};
+use std::ptr::{addr_of_mut, null_mut};
+
+/// One encoded frame.
+pub struct EncodedFrame {
+    /// Annex B byte stream of every NAL unit produced for the frame.
+    pub data: Vec<u8>,
+    pub frame_type: FrameType,
+    /// Temporal layer of the frame's video coding layer.
+    pub temporal_id: u8,
+}
+
+pub struct H264Encoder {
+    api: *mut ISVCEncoder,
+    width: i32,
+    height: i32,
+    /// Picture size written into the SPS frame cropping, set by [`Self::crop_to`].
+    crop: Option<(u32, u32)>,
+    info: SFrameBSInfo,
+}
+
+fn frame_type(raw: c_int) -> FrameType {
+    [
+        (videoFrameTypeIDR, FrameType::IDR),
+        (videoFrameTypeI, FrameType::I),
+        (videoFrameTypeP, FrameType::P),
+        (videoFrameTypeSkip, FrameType::Skip),
+        (videoFrameTypeIPMixed, FrameType::IPMixed),
+    ]
+    .into_iter()
+    .find(|(value, _)| *value == raw)
+    .map_or(FrameType::Invalid, |(_, frame_type)| frame_type)
+}
+
+impl H264Encoder {
+    /// Creates an encoder for `width` x `height` I420 frames.
+    ///
+    /// The parameters start from openh264's defaults plus the same picture size
+    /// and quality-mode rate control `EncoderConfig` sets up, but with frame
+    /// skipping off so every frame given to the encoder comes out coded.
+    /// `adjust` may change any of them before the encoder is initialized.
+    pub fn new(width: u32, height: u32, bitrate_bps: u32, adjust: impl FnOnce(&mut SEncParamExt)) -> Result<Self> {
+        let mut api: *mut ISVCEncoder = null_mut();
+        // Safety: WelsCreateSVCEncoder either fails or leaves a valid encoder in `api`,
+        // which Drop destroys.
+        if unsafe { WelsCreateSVCEncoder(&mut api) } != 0 || api.is_null() {
+            anyhow::bail!("Couldn't create an openh264 encoder");
+        }
+        let mut encoder = Self {
+            api,
+            width: width as i32,
+            height: height as i32,
+            crop: None,
+            info: SFrameBSInfo::default(),
+        };
+
+        let mut params = SEncParamExt::default();
+        unsafe {
+            if (encoder.vtbl().GetDefaultParams.unwrap())(api, &mut params) != 0 {
+                anyhow::bail!("Couldn't read openh264's default encoder parameters");
+            }
+        }
+        params.iPicWidth = encoder.width;
+        params.iPicHeight = encoder.height;
+        params.iRCMode = RC_QUALITY_MODE;
+        params.bEnableFrameSkip = false;
+        params.iTargetBitrate = bitrate_bps as c_int;
+        params.bEnableDenoise = false;
+        params.fMaxFrameRate = 0.0;
+        adjust(&mut params);
+        if params.iPicWidth != encoder.width || params.iPicHeight != encoder.height {
+            anyhow::bail!("The encoder picture size can't be adjusted");
+        }
+
+        unsafe {
+            // Quiet before initializing, which otherwise reports on the parameters to stderr.
+            let mut trace_level = WELS_LOG_QUIET;
+            encoder.set_option(ENCODER_OPTION_TRACE_LEVEL, addr_of_mut!(trace_level).cast())?;
+            if (encoder.vtbl().InitializeExt.unwrap())(api, &params) != 0 {
+                anyhow::bail!("Encoder rejected the requested parameters");
+            }
+            let mut data_format = videoFormatI420;
+            encoder.set_option(ENCODER_OPTION_DATAFORMAT, addr_of_mut!(data_format).cast())?;
+        }
+        Ok(encoder)
+    }
+
+    fn vtbl(&self) -> &ISVCEncoderVtbl {
+        // Safety: `api` was created by WelsCreateSVCEncoder and lives until Drop.
+        unsafe { &**self.api }
+    }
+
+    unsafe fn set_option(&mut self, option: openh264_sys2::ENCODER_OPTION, value: *mut c_void) -> Result<()> {
+        if (self.vtbl().SetOption.unwrap())(self.api, option, value) != 0 {
+            anyhow::bail!("Encoder rejected option {}", option);
+        }
+        Ok(())
+    }
+
+    /// Adjusts parameters of the running encoder, which re-initializes it.
+    /// Settings fixed at initialization, such as the usage type, can't change.
+    pub fn reconfigure(&mut self, adjust: impl FnOnce(&mut SEncParamExt)) -> Result<()> {
+        let mut params = SEncParamExt::default();
+        let option: *mut SEncParamExt = &mut params;
+        unsafe {
+            if (self.vtbl().GetOption.unwrap())(self.api, ENCODER_OPTION_SVC_ENCODE_PARAM_EXT, option.cast()) != 0 {
+                anyhow::bail!("Couldn't read encoder parameters");
+            }
+            adjust(&mut params);
+            if params.iPicWidth != self.width || params.iPicHeight != self.height {
+                anyhow::bail!("The encoder picture size can't be adjusted");
+            }
+            self.set_option(ENCODER_OPTION_SVC_ENCODE_PARAM_EXT, option.cast())
+        }
+    }
+
+    /// Crops the decoded picture to `width` x `height` at the top-left, for frames
+    /// padded up to a size openh264 accepts. openh264 has no setting for this, so
+    /// the frame cropping of every SPS it writes is replaced. Cropping works in
+    /// 2-pixel steps, so odd sizes must be rounded up first.
+    pub fn crop_to(&mut self, width: u32, height: u32) {
+        self.crop = Some((width, height));
+    }
+
+    /// Makes the next frame an IDR frame.
+    pub fn force_keyframe(&mut self) {
+        unsafe {
+            (self.vtbl().ForceIntraFrame.unwrap())(self.api, true);
+        }
+    }
+
+    /// Encodes one frame, which must match the size the encoder was created with.
+    pub fn encode(&mut self, yuv: &impl YUVSource) -> Result<EncodedFrame> {
+        if yuv.width() != self.width || yuv.height() != self.height {
+            anyhow::bail!(
+                "Frame is {}x{} but the encoder was created for {}x{}",
+                yuv.width(),
+                yuv.height(),
+                self.width,
+                self.height
+            );
+        }
+
+        // openh264 only reads the planes, despite the mutable pointers.
+        let source = SSourcePicture {
+            iColorFormat: videoFormatI420,
+            iStride: [yuv.y_stride(), yuv.u_stride(), yuv.v_stride(), 0],
+            pData: [
+                yuv.y().as_ptr() as *mut u8,
+                yuv.u().as_ptr() as *mut u8,
+                yuv.v().as_ptr() as *mut u8,
+                null_mut(),
+            ],
+            iPicWidth: self.width,
+            iPicHeight: self.height,
+            ..Default::default()
+        };
+        unsafe {
+            if (self.vtbl().EncodeFrame.unwrap())(self.api, &source, &mut self.info) != 0 {
+                anyhow::bail!("openh264 failed to encode the frame");
+            }
+        }
+
+        let mut data = Vec::new();
+        let mut temporal_id = None;
+        for layer in &self.info.sLayerInfo[..self.info.iLayerNum as usize] {
+            if layer.uiLayerType == VIDEO_CODING_LAYER as u8 {
+                temporal_id.get_or_insert(layer.uiTemporalId);
+            }
+            // Safety: the encoder owns pBsBuf until the next call and its NAL
+            // lengths add up to the bytes it wrote there.
+            let len: c_int = unsafe { (0..layer.iNalCount as usize).map(|n| *layer.pNalLengthInByte.add(n)).sum() };
+            data.extend_from_slice(unsafe { std::slice::from_raw_parts(layer.pBsBuf, len as usize) });
+        }
+        if let Some((width, height)) = self.crop {
+            data = set_sps_cropping(&data, width, height)?;
+        }
+        Ok(EncodedFrame {
+            data,
+            frame_type: frame_type(self.info.eFrameType),
+            temporal_id: temporal_id.unwrap_or(0),
+        })
+    }
+}
+
+impl Drop for H264Encoder {
+    fn drop(&mut self) {
+        unsafe {
+            (self.vtbl().Uninitialize.unwrap())(self.api);
+            WelsDestroySVCEncoder(self.api);
+        }
+    }
+}
+
+/// NAL unit type of a sequence parameter set.
+const NAL_SPS: u8 = 7;
+
+/// `profile_idc` values whose SPS carries the chroma format and bit depths.
+const HIGH_PROFILES: [u32; 12] = [100, 110, 122, 244, 44, 83, 86, 118, 128, 138, 139, 134];
+
+/// Reads the bits of an RBSP, MSB first.
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl BitReader<'_> {
+    fn bit(&mut self) -> Result<u32> {
+        let byte = self.data.get(self.pos / 8).ok_or_else(|| anyhow::anyhow!("SPS ends early"))?;
+        let bit = (byte >> (7 - self.pos % 8)) & 1;
+        self.pos += 1;
+        Ok(u32::from(bit))
+    }
+
+    fn bits(&mut self, n: u32) -> Result<u32> {
+        (0..n).try_fold(0, |value, _| Ok(value << 1 | self.bit()?))
+    }
+
+    /// An unsigned Exp-Golomb code.
+    fn ue(&mut self) -> Result<u32> {
+        let mut zeros = 0;
+        while self.bit()? == 0 {
+            zeros += 1;
+            if zeros > 31 {
+                anyhow::bail!("SPS has an invalid Exp-Golomb code");
+            }
+        }
+        Ok((1 << zeros) - 1 + self.bits(zeros)?)
+    }
+}
+
+/// Writes the bits of an RBSP, MSB first.
+#[derive(Default)]
+struct BitWriter {
+    data: Vec<u8>,
+    len: usize,
+}
+
+impl BitWriter {
+    fn bit(&mut self, bit: u32) {
+        if self.len.is_multiple_of(8) {
+            self.data.push(0);
+        }
+        if bit != 0 {
+            *self.data.last_mut().unwrap() |= 1 << (7 - self.len % 8);
+        }
+        self.len += 1;
+    }
+
+    fn bits(&mut self, value: u32, n: u32) {
+        for i in (0..n).rev() {
+            self.bit(value >> i & 1);
+        }
+    }
+
+    fn ue(&mut self, value: u32) {
+        let code = u64::from(value) + 1;
+        let len = 64 - code.leading_zeros();
+        self.bits(0, len - 1);
+        for i in (0..len).rev() {
+            self.bit((code >> i & 1) as u32);
+        }
+    }
+}
+
+/// Copies SPS fields from `r` to `w` as they are read.
+struct SpsCopy<'a> {
+    r: BitReader<'a>,
+    w: BitWriter,
+}
+
+impl SpsCopy<'_> {
+    fn bits(&mut self, n: u32) -> Result<u32> {
+        let value = self.r.bits(n)?;
+        self.w.bits(value, n);
+        Ok(value)
+    }
+
+    fn ue(&mut self) -> Result<u32> {
+        let value = self.r.ue()?;
+        self.w.ue(value);
+        Ok(value)
+    }
+
+    /// A signed Exp-Golomb code, copied as its unsigned code number.
+    fn se(&mut self) -> Result<()> {
+        self.ue().map(drop)
+    }
+}
+
+/// Removes the emulation prevention bytes of a NAL unit payload.
+fn unescape(nal: &[u8]) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(nal.len());
+    let mut zeros = 0;
+    for &byte in nal {
+        if zeros >= 2 && byte == 3 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        rbsp.push(byte);
+    }
+    rbsp
+}
+
+/// Adds emulation prevention bytes to an RBSP.
+fn escape(rbsp: &[u8]) -> Vec<u8> {
+    let mut nal = Vec::with_capacity(rbsp.len() + 4);
+    let mut zeros = 0;
+    for &byte in rbsp {
+        if zeros >= 2 && byte <= 3 {
+            nal.push(3);
+            zeros = 0;
+        }
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        nal.push(byte);
+    }
+    nal
+}
+
+/// Rewrites the frame cropping of an SPS RBSP (without its NAL header) so the
+/// picture is `width` x `height` at the top-left of the coded frame.
+fn crop_sps(rbsp: &[u8], width: u32, height: u32) -> Result<Vec<u8>> {
+    let mut sps = SpsCopy {
+        r: BitReader { data: rbsp, pos: 0 },
+        w: BitWriter::default(),
+    };
+    // profile_idc, constraint flags, level_idc
+    let profile_idc = sps.bits(8)?;
+    sps.bits(16)?;
+    sps.ue()?;
+    if HIGH_PROFILES.contains(&profile_idc) {
+        let chroma_format_idc = sps.ue()?;
+        if chroma_format_idc != 1 {
+            anyhow::bail!("SPS isn't 4:2:0");
+        }
+        sps.ue()?;
+        sps.ue()?;
+        sps.bits(1)?;
+        if sps.bits(1)? == 1 {
+            anyhow::bail!("SPS has scaling matrices");
+        }
+    }
+    sps.ue()?;
+    match sps.ue()? {
+        0 => {
+            sps.ue()?;
+        }
+        1 => {
+            sps.bits(1)?;
+            sps.se()?;
+            sps.se()?;
+            for _ in 0..sps.ue()? {
+                sps.se()?;
+            }
+        }
+        _ => {}
+    }
+    // max_num_ref_frames, gaps_in_frame_num_value_allowed_flag
+    sps.ue()?;
+    sps.bits(1)?;
+    let coded_width = (sps.ue()? + 1) * 16;
+    let map_units = sps.ue()? + 1;
+    let frame_mbs_only = sps.bits(1)?;
+    if frame_mbs_only == 0 {
+        sps.bits(1)?;
+    }
+    let coded_height = map_units * 16 * (2 - frame_mbs_only);
+    // direct_8x8_inference_flag
+    sps.bits(1)?;
+
+    // Cropping counts in 2-pixel units across, and 2 or 4 rows down.
+    let crop_unit_y = 2 * (2 - frame_mbs_only);
+    if width > coded_width || height > coded_height || !width.is_multiple_of(2) || !height.is_multiple_of(crop_unit_y) {
+        anyhow::bail!("Can't crop a {}x{} frame to {}x{}", coded_width, coded_height, width, height);
+    }
+    if sps.r.bit()? == 1 {
+        for _ in 0..4 {
+            sps.r.ue()?;
+        }
+    }
+    let (right, bottom) = ((coded_width - width) / 2, (coded_height - height) / crop_unit_y);
+    if right == 0 && bottom == 0 {
+        sps.w.bit(0);
+    } else {
+        sps.w.bit(1);
+        sps.w.ue(0);
+        sps.w.ue(right);
+        sps.w.ue(0);
+        sps.w.ue(bottom);
+    }
+
+    // The VUI and the RBSP stop bit follow unchanged.
+    let stop_bit = rbsp
+        .iter()
+        .rposition(|&byte| byte != 0)
+        .map(|i| i * 8 + 7 - rbsp[i].trailing_zeros() as usize)
+        .ok_or_else(|| anyhow::anyhow!("SPS has no stop bit"))?;
+    while sps.r.pos <= stop_bit {
+        let bit = sps.r.bit()?;
+        sps.w.bit(bit);
+    }
+    Ok(sps.w.data)
+}
+
+/// Replaces the frame cropping of every SPS in the Annex B stream `data`.
+fn set_sps_cropping(data: &[u8], width: u32, height: u32) -> Result<Vec<u8>> {
+    let mut starts = Vec::new();
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i..i + 3] == [0, 0, 1] {
+            starts.push(i + 3);
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+
+    let mut out = Vec::with_capacity(data.len() + 8);
+    let mut copied = 0;
+    for (n, &start) in starts.iter().enumerate() {
+        // A NAL unit ends at the next start code, including the zero of a 4-byte one.
+        let end = match starts.get(n + 1) {
+            Some(&next) if next >= 4 && data[next - 4] == 0 => next - 4,
+            Some(&next) => next - 3,
+            None => data.len(),
+        };
+        if start < end && data[start] & 0x1f == NAL_SPS {
+            out.extend_from_slice(&data[copied..start + 1]);
+            let rbsp = crop_sps(&unescape(&data[start + 1..end]), width, height)
+                .map_err(|e| anyhow::anyhow!("Couldn't set the frame cropping of the SPS: {:#}", e))?;
+            out.extend_from_slice(&escape(&rbsp));
+            copied = end;
+        }
+    }
+    out.extend_from_slice(&data[copied..]);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn emulation_prevention_round_trips() {
+        let rbsp = [0x42, 0, 0, 1, 0, 0, 0, 0, 0, 3, 0, 0];
+        let nal = escape(&rbsp);
+        assert_eq!(nal, [0x42, 0, 0, 3, 1, 0, 0, 3, 0, 0, 3, 0, 3, 0, 0]);
+        assert_eq!(unescape(&nal), rbsp);
+    }
+}
@@ -0,0 +1,204 @@
+//! Removes index records from a finished MCAP for `--message-indexes off` and
+//! `--chunk-indexes off`. The mcap writer always writes both, so the file is
+//! rewritten record by record, dropping the unwanted ones and moving every
+//! offset that points past them.
+
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::io::Write;
+
+const MAGIC: &[u8; 8] = b"\x89MCAP0\r\n";
+
+const OP_FOOTER: u8 = 0x02;
+const OP_MESSAGE_INDEX: u8 = 0x07;
+const OP_CHUNK_INDEX: u8 = 0x08;
+const OP_ATTACHMENT_INDEX: u8 = 0x0a;
+const OP_METADATA_INDEX: u8 = 0x0d;
+const OP_SUMMARY_OFFSET: u8 = 0x0e;
+const OP_DATA_END: u8 = 0x0f;
+
+/// Which index records an output MCAP keeps. Both are on by default.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct IndexOptions {
+    /// The MessageIndex records after each chunk, which let readers find a
+    /// channel's messages in a chunk without decompressing it.
+    pub message_indexes: bool,
+    /// The ChunkIndex records of the summary, which let readers seek to the
+    /// chunks covering a time range instead of reading the file from the start.
+    pub chunk_indexes: bool,
+}
+
+impl Default for IndexOptions {
+    fn default() -> Self {
+        Self {
+            message_indexes: true,
+            chunk_indexes: true,
+        }
+    }
+}
+
+impl IndexOptions {
+    /// Whether every index is kept, so the file needs no rewrite.
+    pub fn keeps_all(&self) -> bool {
+        self.message_indexes && self.chunk_indexes
+    }
+}
+
+struct Record<'a> {
+    offset: u64,
+    opcode: u8,
+    body: &'a [u8],
+}
+
+fn u64_at(body: &[u8], at: usize) -> Result<u64> {
+    let bytes = body.get(at..at + 8).context("Truncated MCAP record")?;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Splits `mapped` into its records, checking the magic at both ends.
+fn records(mapped: &[u8]) -> Result<Vec<Record<'_>>> {
+    if mapped.len() < 2 * MAGIC.len() || !mapped.starts_with(MAGIC) || !mapped.ends_with(MAGIC) {
+        anyhow::bail!("Not a complete MCAP file");
+    }
+    let end = mapped.len() - MAGIC.len();
+    let mut records = Vec::new();
+    let mut offset = MAGIC.len();
+    while offset < end {
+        let opcode = mapped[offset];
+        let len = u64_at(mapped, offset + 1)? as usize;
+        let body = mapped
+            .get(offset + 9..offset + 9 + len)
+            .context("Truncated MCAP record")?;
+        records.push(Record {
+            offset: offset as u64,
+            opcode,
+            body,
+        });
+        offset += 9 + len;
+        if opcode == OP_FOOTER {
+            break;
+        }
+    }
+    Ok(records)
+}
+
+/// Appends a record to `out`, returning its offset.
+fn push_record(out: &mut Vec<u8>, opcode: u8, body: &[u8]) -> u64 {
+    let offset = out.len() as u64;
+    out.push(opcode);
+    out.extend_from_slice(&(body.len() as u64).to_le_bytes());
+    out.extend_from_slice(body);
+    offset
+}
+
+/// Looks up where the record that was at `offset` ended up.
+fn moved(offsets: &HashMap<u64, u64>, offset: u64) -> Result<u64> {
+    offsets
+        .get(&offset)
+        .copied()
+        .ok_or_else(|| anyhow::anyhow!("MCAP index points at no record (offset {})", offset))
+}
+
+/// A ChunkIndex body with its offsets moved, and its message index map
+/// emptied when the message indexes are dropped.
+fn chunk_index(body: &[u8], offsets: &HashMap<u64, u64>, message_indexes: bool) -> Result<Vec<u8>> {
+    let map_len = body.get(32..36).context("Truncated MCAP chunk index")?;
+    let map_len = u32::from_le_bytes(map_len.try_into().unwrap()) as usize;
+    let map = body.get(36..36 + map_len).context("Truncated MCAP chunk index")?;
+    let rest = body.get(36 + map_len..).context("Truncated MCAP chunk index")?;
+
+    let mut out = body[..32].to_vec();
+    out[16..24].copy_from_slice(&moved(offsets, u64_at(body, 16)?)?.to_le_bytes());
+    if message_indexes {
+        out.extend_from_slice(&(map_len as u32).to_le_bytes());
+        for entry in map.chunks(10) {
+            let offset = moved(offsets, u64_at(entry, 2)?)?;
+            out.extend_from_slice(&entry[..2]);
+            out.extend_from_slice(&offset.to_le_bytes());
+        }
+        out.extend_from_slice(rest);
+    } else {
+        out.extend_from_slice(&0u32.to_le_bytes());
+        // message_index_length
+        out.extend_from_slice(&0u64.to_le_bytes());
+        out.extend_from_slice(rest.get(8..).context("Truncated MCAP chunk index")?);
+    }
+    Ok(out)
+}
+
+/// Writes `mapped` to `output` without the index records `keep` turns off.
+/// The summary offsets are rebuilt for what remains, and the summary CRC is
+/// recomputed. The data section CRC is left unset, as the mcap writer does.
+pub fn strip_indexes<W: Write>(mapped: &[u8], mut output: W, keep: IndexOptions) -> Result<()> {
+    let records = records(mapped)?;
+    let footer = records
+        .last()
+        .filter(|r| r.opcode == OP_FOOTER)
+        .context("MCAP has no footer")?;
+    let summary_start = u64_at(footer.body, 0)?;
+
+    let mut out = MAGIC.to_vec();
+    // Input offset -> output offset of every record kept in the data section.
+    let mut offsets = HashMap::new();
+    let mut new_summary_start = 0;
+    // (opcode, start, length) of each run of same-opcode summary records.
+    let mut groups: Vec<(u8, u64, u64)> = Vec::new();
+    for record in &records[..records.len() - 1] {
+        let in_summary = summary_start != 0 && record.offset >= summary_start;
+        if !in_summary {
+            let body = match record.opcode {
+                OP_MESSAGE_INDEX if !keep.message_indexes => continue,
+                OP_DATA_END => &[0u8; 4][..],
+                _ => record.body,
+            };
+            offsets.insert(record.offset, push_record(&mut out, record.opcode, body));
+            continue;
+        }
+
+        let body = match record.opcode {
+            OP_SUMMARY_OFFSET => continue,
+            OP_CHUNK_INDEX if !keep.chunk_indexes => continue,
+            OP_CHUNK_INDEX => chunk_index(record.body, &offsets, keep.message_indexes)?,
+            OP_ATTACHMENT_INDEX | OP_METADATA_INDEX => {
+                let mut body = record.body.to_vec();
+                body[..8].copy_from_slice(&moved(&offsets, u64_at(record.body, 0)?)?.to_le_bytes());
+                body
+            }
+            _ => record.body.to_vec(),
+        };
+        if new_summary_start == 0 {
+            new_summary_start = out.len() as u64;
+        }
+        let offset = push_record(&mut out, record.opcode, &body);
+        let length = out.len() as u64 - offset;
+        match groups.last_mut() {
+            Some((opcode, _, group_length)) if *opcode == record.opcode => *group_length += length,
+            _ => groups.push((record.opcode, offset, length)),
+        }
+    }
+
+    let summary_offset_start = match groups.is_empty() {
+        true => 0,
+        false => out.len() as u64,
+    };
+    for (opcode, start, length) in groups {
+        let mut body = vec![opcode];
+        body.extend_from_slice(&start.to_le_bytes());
+        body.extend_from_slice(&length.to_le_bytes());
+        push_record(&mut out, OP_SUMMARY_OFFSET, &body);
+    }
+
+    out.push(OP_FOOTER);
+    out.extend_from_slice(&20u64.to_le_bytes());
+    out.extend_from_slice(&new_summary_start.to_le_bytes());
+    out.extend_from_slice(&summary_offset_start.to_le_bytes());
+    let crc = match new_summary_start {
+        0 => 0,
+        start => crc32fast::hash(&out[start as usize..]),
+    };
+    out.extend_from_slice(&crc.to_le_bytes());
+    out.extend_from_slice(MAGIC);
+
+    output.write_all(&out)?;
+    Ok(())
+}
@@ -0,0 +1,64 @@
+//! Default Foxglove Studio layout written by `--foxglove-layout`.
+
+use crate::json_string;
+use std::collections::BTreeMap;
+
+/// Name of the MCAP metadata record holding the layout.
+pub const METADATA_NAME: &str = "foxglove.layout";
+
+/// Splits `panel_ids` into a balanced tree of mosaic nodes, alternating
+/// between rows and columns so the panels form a grid.
+fn mosaic(panel_ids: &[String], row: bool) -> String {
+    if panel_ids.len() == 1 {
+        return json_string(&panel_ids[0]);
+    }
+    let half = panel_ids.len().div_ceil(2);
+    format!(
+        "{{\"first\":{},\"second\":{},\"direction\":\"{}\",\"splitPercentage\":{:.1}}}",
+        mosaic(&panel_ids[..half], !row),
+        mosaic(&panel_ids[half..], !row),
+        if row { "row" } else { "column" },
+        100.0 * half as f64 / panel_ids.len() as f64
+    )
+}
+
+/// Builds a layout with one Image panel per video topic, topics sorted by name.
+pub fn multi_camera_layout<'a>(video_topics: impl IntoIterator<Item = &'a String>) -> String {
+    let mut topics: Vec<&String> = video_topics.into_iter().collect();
+    topics.sort();
+
+    let panel_ids: Vec<String> = (0..topics.len()).map(|i| format!("Image!video{}", i)).collect();
+    let config_by_id: Vec<String> = panel_ids
+        .iter()
+        .zip(&topics)
+        .map(|(id, topic)| {
+            format!(
+                "{}:{{\"imageMode\":{{\"imageTopic\":{}}}}}",
+                json_string(id),
+                json_string(topic)
+            )
+        })
+        .collect();
+
+    let layout = if panel_ids.is_empty() {
+        "null".to_string()
+    } else {
+        mosaic(&panel_ids, true)
+    };
+
+    format!(
+        "{{\"configById\":{{{}}},\"layout\":{},\"globalVariables\":{{}},\"userNodes\":{{}},\"playbackConfig\":{{\"speed\":1}}}}",
+        config_by_id.join(","),
+        layout
+    )
+}
+
+/// Wraps the layout in the metadata record written to the output MCAP.
+pub fn metadata<'a>(video_topics: impl IntoIterator<Item = &'a String>) -> mcap::records::Metadata {
+    let mut metadata = BTreeMap::new();
+    metadata.insert("layout".to_string(), multi_camera_layout(video_topics));
+    mcap::records::Metadata {
+        name: METADATA_NAME.to_string(),
+        metadata,
+    }
+}
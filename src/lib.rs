@@ -0,0 +1,1767 @@
+//! Converts the image topics of an MCAP file to compressed video.
+//!
+//! [`convert`] is the whole pipeline behind the `mcap-videoify` command line:
+//! it reads an MCAP, re-encodes every `foxglove.CompressedImage` and
+//! `foxglove.RawImage` topic as video and copies everything else. It never
+//! prints or exits; progress lines and warnings go to [`ConvertOptions::log`].
+
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+use image::io::Reader as ImageReader;
+use image::RgbImage;
+use openh264::encoder::FrameType;
+use openh264::formats::YUVBuffer;
+use openh264_sys2::{CAMERA_VIDEO_REAL_TIME, CONSTANT_ID, SCREEN_CONTENT_REAL_TIME};
+use protobuf::descriptor::FileDescriptorSet;
+use protobuf::reflect::{FileDescriptor, MessageDescriptor};
+use protobuf::Message;
+use std::borrow::Cow;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{BufWriter, Cursor, Seek, Write};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+
+pub use frame::{ErrorImage, SizeLimit, ToneAdjust, Tonemap};
+pub use indexes::{strip_indexes, IndexOptions};
+pub use timing::parse_duration;
+
+#[allow(renamed_and_removed_lints)]
+mod foxglove {
+    include!(concat!(env!("OUT_DIR"), "/generated_protos/mod.rs"));
+}
+
+/// Passes a progress or report line to [`ConvertOptions::log`].
+macro_rules! status {
+    ($opts:expr, $($arg:tt)*) => {
+        $opts.log(LogLevel::Status, &format!($($arg)*))
+    };
+}
+
+/// Passes a warning to [`ConvertOptions::log`].
+macro_rules! warning {
+    ($opts:expr, $($arg:tt)*) => {
+        $opts.log(LogLevel::Warning, &format!($($arg)*))
+    };
+}
+
+mod counts;
+mod frame;
+mod h264;
+mod indexes;
+mod layout;
+mod meta;
+mod mp4;
+mod profile;
+mod reorder;
+mod source_ref;
+mod timing;
+mod title;
+mod worker;
+mod yuv;
+
+/// Kind of a line passed to [`ConvertOptions::log`].
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LogLevel {
+    /// Progress and report lines. The per-message ones are left out when
+    /// [`ConvertOptions::silent`] is set.
+    Status,
+    /// Something worth knowing that doesn't stop the conversion, such as images
+    /// left as-is or a run cut short by the time budget.
+    Warning,
+}
+
+/// Receives the lines a conversion logs.
+pub type LogFn = dyn Fn(LogLevel, &str) + Send + Sync;
+
+/// Settings of a conversion. The defaults match the command line's.
+pub struct ConvertOptions {
+    /// Receives progress lines and warnings; without it they are dropped.
+    pub log: Option<Box<LogFn>>,
+    /// Leave out the per-message progress lines.
+    pub silent: bool,
+    /// Create missing parent directories of the files written by path.
+    pub create_dirs: bool,
+    /// Chunking and compression of the output MCAP.
+    pub write_options: mcap::WriteOptions,
+    /// Header profile of the output MCAP; `None` keeps the input's.
+    pub mcap_profile: Option<String>,
+    /// Schema encodings decoded as images.
+    pub source_encodings: Vec<String>,
+    /// CompressedImage descriptor for channels without a usable embedded schema.
+    pub external_schema: Option<MessageDescriptor>,
+    /// Write schema records for passed-through channels.
+    pub copy_schema_records: bool,
+    /// Leave out video channels of image topics that produce no frames.
+    pub drop_empty_topics: bool,
+    /// Input image topic -> output video topic, instead of `<topic>_video`.
+    pub topic_renames: HashMap<String, String>,
+    /// Let several input topics be renamed to the same output topic.
+    pub allow_topic_merge: bool,
+    /// H.264 encoder settings shared by every topic.
+    pub encoder: EncoderSettings,
+    /// Output codec of the video topics.
+    pub codec: Codec,
+    /// JPEG quality, 1-100, of [`Codec::Mjpeg`] frames and JPEG thumbnails.
+    pub jpeg_quality: u8,
+    /// Force a keyframe every this many frames per topic; 0 leaves it to the encoder.
+    pub keyframe_interval: u64,
+    /// Write only keyframes; needs a `keyframe_interval`.
+    pub only_keyframes: bool,
+    /// Force a keyframe once the frames a topic emitted after its last keyframe
+    /// reach this many bytes; the keyframe itself is not counted.
+    pub max_gop_bytes: Option<usize>,
+    /// Pad YUV plane rows to a multiple of this many bytes; `None` packs them tightly.
+    pub yuv_alignment: Option<usize>,
+    /// Caps on the encoded frame size.
+    pub size_limit: SizeLimit,
+    /// Gamma, brightness and contrast applied to every frame.
+    pub tone: ToneAdjust,
+    /// Reduce every frame to a palette of this many colours (2-256) per topic.
+    pub palette_size: Option<usize>,
+    /// Tone-map 16-bit and float images to 8 bits instead of truncating them.
+    pub tonemap: Option<Tonemap>,
+    /// Copy 16-bit and float images unchanged instead of encoding them.
+    pub preserve_hdr: bool,
+    /// Encode every frame of an animated GIF or APNG instead of failing on it.
+    pub expand_animations: bool,
+    /// Copy images openh264 can't encode (under 16x16 or odd-sized) unchanged
+    /// instead of padding them.
+    pub pass_through_small_images: bool,
+    /// What happens when a topic changes image format, pixel layout or resolution.
+    pub on_format_change: FormatChange,
+    /// What happens to an image message that fails to parse, decode or encode.
+    pub on_error: OnError,
+    /// Encoded in place of undecodable images, scaled to the topic's size.
+    pub error_image: Option<ErrorImage>,
+    /// Path of the CSV listing every output video frame.
+    pub frame_index_csv: Option<String>,
+    /// Embed a Foxglove layout of the video topics as metadata.
+    pub foxglove_layout: bool,
+    /// Attach a small JPEG thumbnail of every keyframe.
+    pub embed_keyframe_thumbs: bool,
+    /// Embed the source topic, sequence and log_time in every encoded frame.
+    pub embed_source_ref: bool,
+    /// Write a JSON `<topic>_video_meta` message per frame.
+    pub emit_frame_meta: bool,
+    /// Stop reading new messages once the conversion has run this long.
+    pub time_budget: Option<Duration>,
+    /// Start a new output every this many nanoseconds of log_time; see [`convert_segments`].
+    pub segment_duration: Option<u64>,
+    /// Reorder output messages by log_time within this many nanoseconds.
+    pub reorder_window: Option<u64>,
+    /// Replace a zero publish_time with log_time on every written message.
+    pub fix_publish_time: bool,
+    /// Report the log_time ordering of the input and output.
+    pub check_timing: bool,
+    /// Report the time spent in each stage.
+    pub profile_report: bool,
+}
+
+impl Default for ConvertOptions {
+    fn default() -> Self {
+        Self {
+            log: None,
+            silent: false,
+            create_dirs: false,
+            write_options: mcap::WriteOptions::new(),
+            mcap_profile: None,
+            source_encodings: SUPPORTED_SOURCE_ENCODINGS.iter().map(|e| e.to_string()).collect(),
+            external_schema: None,
+            copy_schema_records: true,
+            drop_empty_topics: true,
+            topic_renames: HashMap::new(),
+            allow_topic_merge: false,
+            encoder: EncoderSettings {
+                temporal_layers: 1,
+                usage_type: UsageType::Camera,
+                bitrate: None,
+                quality: None,
+                frame_skip: false,
+            },
+            codec: Codec::H264,
+            jpeg_quality: 85,
+            keyframe_interval: 0,
+            only_keyframes: false,
+            max_gop_bytes: None,
+            yuv_alignment: None,
+            size_limit: SizeLimit::default(),
+            tone: ToneAdjust::default(),
+            palette_size: None,
+            tonemap: None,
+            preserve_hdr: false,
+            expand_animations: false,
+            pass_through_small_images: false,
+            on_format_change: FormatChange::Reinit,
+            on_error: OnError::Skip,
+            error_image: None,
+            frame_index_csv: None,
+            foxglove_layout: false,
+            embed_keyframe_thumbs: false,
+            embed_source_ref: false,
+            emit_frame_meta: false,
+            time_budget: None,
+            segment_duration: None,
+            reorder_window: None,
+            fix_publish_time: false,
+            check_timing: false,
+            profile_report: false,
+        }
+    }
+}
+
+impl ConvertOptions {
+    fn log(&self, level: LogLevel, line: &str) {
+        if let Some(log) = &self.log {
+            log(level, line);
+        }
+    }
+
+    /// Rejects combinations of settings that can't work together.
+    pub fn validate(&self) -> Result<()> {
+        #[cfg(feature = "raw-codec")]
+        if self.codec == Codec::Raw && self.embed_source_ref {
+            anyhow::bail!("--embed-source-ref isn't supported with --codec raw");
+        }
+        if self.preserve_hdr && self.tonemap.is_some() {
+            anyhow::bail!("--preserve-hdr and --tonemap are mutually exclusive");
+        }
+        if self.only_keyframes && self.keyframe_interval == 0 {
+            anyhow::bail!("--only-keyframes-to-mcap requires --keyframe-interval");
+        }
+        Ok(())
+    }
+
+    /// Write options with the header profile resolved against the input.
+    fn resolved_write_options(&self, mapped: &[u8]) -> Result<mcap::WriteOptions> {
+        let profile = match &self.mcap_profile {
+            Some(profile) => profile.clone(),
+            None => input_profile(mapped)?,
+        };
+        Ok(self.write_options.clone().profile(profile))
+    }
+}
+
+/// Lines logged while converting one message, held until the message is
+/// written so the log follows the input order whichever thread did the work.
+#[derive(Default)]
+struct LogBuffer(Vec<(LogLevel, String)>);
+
+impl LogBuffer {
+    fn log(&mut self, level: LogLevel, line: &str) {
+        self.0.push((level, line.to_string()));
+    }
+
+    fn replay(self, opts: &ConvertOptions) {
+        for (level, line) in self.0 {
+            opts.log(level, &line);
+        }
+    }
+}
+
+/// Message counts of a finished conversion.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct ConvertStats {
+    /// Image messages decoded and handed to the encoder.
+    pub transcoded_messages: u64,
+    /// Messages copied unchanged, including images that were left as-is.
+    pub passed_through_messages: u64,
+    /// Image messages dropped by a skipped format change.
+    pub skipped_messages: u64,
+    /// Image messages that failed to parse, decode or encode, and were
+    /// dropped or copied unchanged according to [`ConvertOptions::on_error`].
+    pub failed_messages: u64,
+    /// Video frames written to the output.
+    pub video_frames: u64,
+    /// Whether the time budget, or input that couldn't be read, stopped the
+    /// conversion before the end of the input.
+    pub stopped_early: bool,
+}
+
+/// Converts the MCAP in `input`, such as a memory-mapped file, into `output`.
+/// The output needs `Seek` because the MCAP writer goes back to fill in its
+/// lengths. A `segment_duration` needs one writer per segment, so it is only
+/// accepted by [`convert_segments`].
+pub fn convert<W: Write + Seek>(input: &[u8], output: W, opts: &ConvertOptions) -> Result<ConvertStats> {
+    if opts.segment_duration.is_some() {
+        anyhow::bail!("segment_duration writes several outputs; use convert_segments");
+    }
+    let mut output = Some(output);
+    convert_segments(input, |_| Ok(output.take().expect("only one output without segments")), opts)
+}
+
+/// Quotes `s` as a JSON string.
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Quotes `s` as a CSV field when it holds a comma, quote or line break (RFC 4180).
+fn csv_field(s: &str) -> Cow<'_, str> {
+    if s.contains([',', '"', '\n', '\r']) {
+        Cow::from(format!("\"{}\"", s.replace('"', "\"\"")))
+    } else {
+        Cow::from(s)
+    }
+}
+
+/// openh264 usage type selected with `--usage-type`.
+#[derive(Clone, Copy, PartialEq)]
+pub enum UsageType {
+    /// CAMERA_VIDEO_REAL_TIME, openh264's default.
+    Camera,
+    /// SCREEN_CONTENT_REAL_TIME, tuned for rendered UIs and plots.
+    Screen,
+    /// Camera usage with the per-frame analysis that adds encode time turned off.
+    Realtime,
+}
+
+/// Encoder options shared by every topic.
+pub struct EncoderSettings {
+    /// Number of temporal SVC layers; 1 disables temporal scalability.
+    pub temporal_layers: i32,
+    pub usage_type: UsageType,
+    /// Target bitrate from `--bitrate`, which takes precedence over `quality`.
+    pub bitrate: Option<u32>,
+    pub quality: Option<Quality>,
+    /// Let rate control drop frames to stay within the bitrate, from `--allow-frame-skip`.
+    pub frame_skip: bool,
+}
+
+/// Frame rate the encoder spreads a `--bitrate` or `--quality` budget over.
+const ASSUMED_FRAME_RATE: f32 = 30.0;
+
+/// Bitrate tier selected with `--quality`.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Quality {
+    Low,
+    Medium,
+    High,
+}
+
+impl Quality {
+    /// Bits per pixel of each frame at `ASSUMED_FRAME_RATE`.
+    fn bits_per_pixel(self) -> f64 {
+        match self {
+            Quality::Low => 0.05,
+            Quality::Medium => 0.1,
+            Quality::High => 0.2,
+        }
+    }
+}
+
+impl EncoderSettings {
+    /// Target bitrate for a `width` x `height` topic: `--bitrate` if given,
+    /// else scaled to the frame size by `--quality`, else 10 Mbps.
+    fn bitrate_bps(&self, width: u32, height: u32) -> u32 {
+        match (self.bitrate, self.quality) {
+            (Some(bitrate), _) => bitrate,
+            (None, Some(quality)) => {
+                let pixels_per_second = f64::from(width) * f64::from(height) * f64::from(ASSUMED_FRAME_RATE);
+                let bps = pixels_per_second * quality.bits_per_pixel();
+                bps.round().clamp(1.0, f64::from(i32::MAX)) as u32
+            }
+            (None, None) => 10_000_000,
+        }
+    }
+}
+
+/// Creates an encoder for `width` x `height` frames. A non-zero `keyframe_interval`
+/// sets openh264's own IDR period to match the keyframes forced every that many frames.
+fn new_encoder(width: u32, height: u32, settings: &EncoderSettings, keyframe_interval: u64) -> Result<h264::H264Encoder> {
+    h264::H264Encoder::new(width, height, settings.bitrate_bps(width, height), |params| {
+        if keyframe_interval > 0 {
+            params.uiIntraPeriod = keyframe_interval.min(u64::from(u32::MAX)) as u32;
+        }
+        if settings.bitrate.is_some() || settings.quality.is_some() {
+            // openh264 leaves the bitrate unenforced unless it knows a frame rate.
+            params.fMaxFrameRate = ASSUMED_FRAME_RATE;
+        }
+        if settings.frame_skip {
+            params.bEnableFrameSkip = true;
+        }
+        if settings.temporal_layers > 1 {
+            params.iTemporalLayerNum = settings.temporal_layers;
+        }
+        match settings.usage_type {
+            UsageType::Camera => params.iUsageType = CAMERA_VIDEO_REAL_TIME,
+            UsageType::Screen => params.iUsageType = SCREEN_CONTENT_REAL_TIME,
+            UsageType::Realtime => {
+                params.iUsageType = CAMERA_VIDEO_REAL_TIME;
+                params.iNumRefFrame = 1;
+                params.bEnableSceneChangeDetect = false;
+                params.bEnableBackgroundDetection = false;
+                params.bEnableAdaptiveQuant = false;
+            }
+        }
+    })
+}
+
+/// Output codec selected with `--codec`.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Codec {
+    /// H.264 via openh264, written as foxglove.CompressedVideo.
+    H264,
+    /// Independent JPEG frames, written as foxglove.CompressedImage.
+    Mjpeg,
+    /// Test-only passthrough of the processed RGB pixels, written as
+    /// foxglove.CompressedVideo with format `rgb8`; see [`raw_frame`].
+    #[cfg(feature = "raw-codec")]
+    Raw,
+}
+
+/// Wraps an RGB frame for `--codec raw`: width and height as little-endian
+/// u32s, then the packed rows.
+#[cfg(feature = "raw-codec")]
+fn raw_frame(img: &RgbImage) -> Vec<u8> {
+    let mut data = Vec::with_capacity(8 + img.as_raw().len());
+    data.extend_from_slice(&img.width().to_le_bytes());
+    data.extend_from_slice(&img.height().to_le_bytes());
+    data.extend_from_slice(img.as_raw());
+    data
+}
+
+/// What `--on-format-change` does when a topic's source images switch container
+/// format or pixel layout mid-stream.
+#[derive(Clone, Copy, PartialEq)]
+pub enum FormatChange {
+    /// Start a fresh encoder, so the first frame in the new format is an IDR frame.
+    Reinit,
+    Error,
+    /// Drop frames that don't match the topic's first format.
+    Skip,
+}
+
+/// What `--on-error` does with an image message that fails to parse, decode or encode.
+#[derive(Clone, Copy, PartialEq)]
+pub enum OnError {
+    /// Stop the conversion with the error.
+    Fail,
+    /// Drop the message, leaving a gap in the video. The default.
+    Skip,
+    /// Copy the message to the output unchanged.
+    PassThrough,
+}
+
+impl OnError {
+    /// Handles `error`, a failure converting one message: returns it under
+    /// `Fail`, and otherwise logs it and returns whether to copy the message as-is.
+    fn recover(self, silent: bool, log: &mut LogBuffer, error: anyhow::Error) -> Result<bool> {
+        let pass_through = match self {
+            OnError::Fail => anyhow::bail!("{:#}. Use --on-error skip or passthrough to continue", error),
+            OnError::Skip => false,
+            OnError::PassThrough => true,
+        };
+        if !silent {
+            let action = if pass_through { "leaving it as-is" } else { "skipping it" };
+            status!(log, "{:#}; {}", error, action);
+        }
+        Ok(pass_through)
+    }
+}
+
+/// Builds a protobuf MCAP schema for a message defined in `file`.
+fn protobuf_schema(name: &str, file: &FileDescriptor) -> mcap::Schema<'static> {
+    // Dependencies first, as `protoc --include_imports` orders them.
+    let mut set = FileDescriptorSet::new();
+    set.file.push(
+        ::protobuf::well_known_types::timestamp::file_descriptor()
+            .proto()
+            .clone(),
+    );
+    set.file.push(file.proto().clone());
+
+    mcap::Schema {
+        name: name.to_string(),
+        encoding: "protobuf".to_string(),
+        data: Cow::from(set.write_to_bytes().unwrap()),
+    }
+}
+
+/// Schema encodings we know how to decode a foxglove.CompressedImage from.
+pub const SUPPORTED_SOURCE_ENCODINGS: &[&str] = &["protobuf"];
+
+pub fn parse_source_encodings(value: &str) -> Result<Vec<String>> {
+    let mut encodings = Vec::new();
+    for encoding in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        if !SUPPORTED_SOURCE_ENCODINGS.contains(&encoding) {
+            anyhow::bail!(
+                "Unsupported source encoding: {}. Supported encodings: {}",
+                encoding,
+                SUPPORTED_SOURCE_ENCODINGS.join(",")
+            );
+        }
+        encodings.push(encoding.to_string());
+    }
+    Ok(encodings)
+}
+
+/// Schema names of the image messages that are converted to video.
+const IMAGE_SCHEMAS: [&str; 2] = ["foxglove.CompressedImage", RAW_IMAGE_SCHEMA];
+
+/// Uncompressed images, whose pixels are read directly rather than decoded.
+const RAW_IMAGE_SCHEMA: &str = "foxglove.RawImage";
+
+/// Whether messages on a channel with this schema should be decoded and re-encoded as video.
+fn is_convertible(schema: &mcap::Schema, source_encodings: &[String]) -> bool {
+    IMAGE_SCHEMAS.contains(&schema.name.as_str()) && source_encodings.contains(&schema.encoding)
+}
+
+/// Like [`is_convertible`], for a topic's first channel as recorded in its counts.
+fn is_convertible_topic(count: &counts::TopicCount, source_encodings: &[String]) -> bool {
+    count.schema.as_deref().is_some_and(|s| IMAGE_SCHEMAS.contains(&s))
+        && count.schema_encoding.as_ref().is_some_and(|e| source_encodings.contains(e))
+}
+
+/// Collects the `log_time` sequence of every convertible topic without writing any output.
+fn scan_timing(mapped: &[u8], source_encodings: &[String]) -> Result<timing::TimingLog> {
+    let mut log = timing::TimingLog::default();
+    for message in mcap::MessageStream::new(mapped)? {
+        let message = message?;
+        if let Some(schema) = &message.channel.schema {
+            if is_convertible(schema, source_encodings) {
+                log.record(&message.channel.topic, message.log_time);
+            }
+        }
+    }
+    Ok(log)
+}
+
+/// Creates `path` for writing. A missing parent directory is created when
+/// `create_dirs` is set and reported clearly otherwise, rather than surfacing
+/// the OS's bare "No such file or directory".
+pub fn create_output_file(path: &str, create_dirs: bool) -> Result<File> {
+    if let Some(parent) = Utf8Path::new(path).parent().filter(|p| !p.as_str().is_empty()) {
+        if !parent.is_dir() {
+            if !create_dirs {
+                anyhow::bail!(
+                    "output directory does not exist: {} (use --create-dirs to create it)",
+                    parent
+                );
+            }
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Couldn't create output directory {}", parent))?;
+        }
+    }
+    File::create(path).with_context(|| format!("Couldn't create {}", path))
+}
+
+/// Loads a serialized `FileDescriptorSet`, as produced by `protoc --descriptor_set_out`,
+/// and returns the CompressedImage descriptor it defines.
+pub fn load_external_schema(path: &str) -> Result<MessageDescriptor> {
+    let bytes = fs::read(path).with_context(|| format!("Couldn't read schema file {}", path))?;
+    let set = FileDescriptorSet::parse_from_bytes(&bytes)
+        .with_context(|| format!("{} is not a serialized FileDescriptorSet", path))?;
+    let descriptors = FileDescriptor::new_dynamic_fds(set.file, &[])
+        .with_context(|| format!("Couldn't build descriptors from {}", path))?;
+    descriptors
+        .iter()
+        .find_map(|d| d.message_by_full_name(".foxglove.CompressedImage"))
+        .ok_or_else(|| anyhow::anyhow!("{} does not define foxglove.CompressedImage", path))
+}
+
+/// Channel record for the output video topic `topic`.
+fn video_channel(topic: &str, schema: &mcap::Schema<'static>) -> Arc<mcap::Channel<'static>> {
+    Arc::new(mcap::Channel {
+        schema: Some(Arc::new(schema.clone())),
+        topic: topic.to_string(),
+        message_encoding: "protobuf".to_string(),
+        metadata: std::collections::BTreeMap::new(),
+    })
+}
+
+/// Resolves the descriptor of message `name` embedded in a channel's schema data,
+/// returning `None` when the data is empty or doesn't describe the message.
+fn in_file_schema(data: &[u8], name: &str) -> Result<Option<MessageDescriptor>> {
+    if data.is_empty() {
+        return Ok(None);
+    }
+    let set_proto = FileDescriptorSet::parse_from_bytes(data)?;
+    let descriptors = FileDescriptor::new_dynamic_fds(set_proto.file, &[])?;
+
+    // The message can be defined in any file of the set, not only the one after
+    // its google.protobuf.Timestamp dependency.
+    let full_name = format!(".{}", name);
+    Ok(descriptors.iter().find_map(|d| d.message_by_full_name(&full_name)))
+}
+
+/// Picks the image descriptor for a channel. The embedded schema wins;
+/// `external` (from `--schema`) only rescues CompressedImage channels without a usable one.
+fn resolve_descriptor(
+    schema: &mcap::Schema,
+    external: Option<&MessageDescriptor>,
+    topic: &str,
+) -> Result<MessageDescriptor> {
+    if schema.name == RAW_IMAGE_SCHEMA {
+        return in_file_schema(&schema.data, &schema.name)
+            .map_err(|e| anyhow::anyhow!("Couldn't parse the embedded schema {} of topic {}: {:#}", schema.name, topic, e))?
+            .ok_or_else(|| anyhow::anyhow!("No {} schema for topic {}", RAW_IMAGE_SCHEMA, topic));
+    }
+    let in_file = match external {
+        Some(_) => in_file_schema(&schema.data, &schema.name).unwrap_or(None),
+        None => in_file_schema(&schema.data, &schema.name).map_err(|e| {
+            anyhow::anyhow!("Couldn't parse the embedded schema {} of topic {}: {:#}", schema.name, topic, e)
+        })?,
+    };
+    in_file.or_else(|| external.cloned()).ok_or_else(|| {
+        anyhow::anyhow!(
+            "No foxglove.CompressedImage schema for topic {}. Use --schema to supply one",
+            topic
+        )
+    })
+}
+
+/// Descriptors already resolved by [`resolve_descriptor`], keyed by the whole
+/// schema record. A recording spanning a schema upgrade can carry several
+/// `foxglove.CompressedImage` definitions, and each channel gets the one its
+/// own schema describes.
+struct DescriptorCache<'a> {
+    external: Option<&'a MessageDescriptor>,
+    descriptors: HashMap<Arc<mcap::Schema<'a>>, MessageDescriptor>,
+}
+
+impl<'a> DescriptorCache<'a> {
+    fn new(external: Option<&'a MessageDescriptor>) -> Self {
+        Self {
+            external,
+            descriptors: HashMap::new(),
+        }
+    }
+
+    fn get(&mut self, schema: &Arc<mcap::Schema<'a>>, topic: &str) -> Result<MessageDescriptor> {
+        if let Some(descriptor) = self.descriptors.get(schema) {
+            return Ok(descriptor.clone());
+        }
+        let descriptor = resolve_descriptor(schema, self.external, topic)?;
+        self.descriptors.insert(schema.clone(), descriptor.clone());
+        Ok(descriptor)
+    }
+}
+
+/// MCAP header profiles accepted by `--mcap-profile`.
+pub const MCAP_PROFILES: &[&str] = &["", "foxglove", "ros1", "ros2"];
+
+/// Reads the profile from the input's header record.
+fn input_profile(mapped: &[u8]) -> Result<String> {
+    match mcap::read::LinearReader::new(mapped)?.next() {
+        Some(Ok(mcap::records::Record::Header(header))) => Ok(header.profile),
+        Some(Err(e)) => Err(e).context("Couldn't read MCAP header"),
+        _ => anyhow::bail!("Input MCAP does not start with a header record"),
+    }
+}
+
+pub fn parse_compression(value: &str) -> Result<Option<mcap::Compression>> {
+    match value {
+        "zstd" => Ok(Some(mcap::Compression::Zstd)),
+        "lz4" => Ok(Some(mcap::Compression::Lz4)),
+        "none" => Ok(None),
+        _ => anyhow::bail!("Invalid value for --compression: {}. Expected zstd, lz4 or none", value),
+    }
+}
+
+/// Copies every message, attachment and metadata record from `mapped` into a new
+/// MCAP written with `write_options`. Message payloads are never decoded, so this
+/// is lossless and only the container layout (chunking, compression) changes.
+pub fn repack(mapped: &[u8], output_path: &str, opts: &ConvertOptions) -> Result<()> {
+    let mut out = opts
+        .resolved_write_options(mapped)?
+        .create(BufWriter::new(create_output_file(output_path, opts.create_dirs)?))
+        .context("Couldn't create output MCAP")?;
+
+    let mut message_count = 0;
+    for message in mcap::MessageStream::new(mapped)? {
+        out.write(&message?)?;
+        message_count += 1;
+    }
+
+    if let Some(summary) = mcap::Summary::read(mapped)? {
+        for index in &summary.attachment_indexes {
+            out.attach(&mcap::read::attachment(mapped, index)?)?;
+        }
+        for index in &summary.metadata_indexes {
+            out.write_metadata(&mcap::read::metadata(mapped, index)?)?;
+        }
+    }
+
+    out.finish()?;
+
+    if !opts.silent {
+        status!(opts, "Repacked {} messages into {}", message_count, output_path);
+    }
+    Ok(())
+}
+
+/// Image format of the CompressedImage frames written by [`decode`].
+#[derive(Clone, Copy, PartialEq)]
+pub enum DecodeFormat {
+    Png,
+    /// At [`ConvertOptions::jpeg_quality`].
+    Jpeg,
+}
+
+/// Undoes the conversion: decodes every h264 `foxglove.CompressedVideo` message
+/// of `mapped` and writes its frame as a `foxglove.CompressedImage` on the
+/// topic with the `_video` suffix stripped. Timestamps, frame_id and sequence
+/// are kept; every other message is copied unchanged.
+pub fn decode(mapped: &[u8], output_path: &str, format: DecodeFormat, opts: &ConvertOptions) -> Result<()> {
+    let mut out = opts
+        .resolved_write_options(mapped)?
+        .create(BufWriter::new(create_output_file(output_path, opts.create_dirs)?))
+        .context("Couldn't create output MCAP")?;
+    let image_schema = protobuf_schema("foxglove.CompressedImage", foxglove::CompressedImage::file_descriptor());
+
+    // Map of video topic -> decoder and output channel of its image topic
+    let mut topics: HashMap<String, (openh264::decoder::Decoder, Arc<mcap::Channel>)> = HashMap::new();
+    let mut decoded_frames = 0u64;
+    let mut passed_through = 0u64;
+    for message in mcap::MessageStream::new(mapped)? {
+        let message = message?;
+        let topic = &message.channel.topic;
+        let is_video = message
+            .channel
+            .schema
+            .as_ref()
+            .is_some_and(|s| s.name == "foxglove.CompressedVideo" && s.encoding == "protobuf");
+        let video = match is_video {
+            true => Some(
+                foxglove::CompressedVideo::CompressedVideo::parse_from_bytes(&message.data)
+                    .map_err(|e| anyhow::anyhow!("Couldn't parse message {} on {}: {:#}", message.sequence, topic, e))?,
+            ),
+            false => None,
+        };
+        let Some(video) = video.filter(|v| v.format == "h264") else {
+            out.write(&message)?;
+            passed_through += 1;
+            continue;
+        };
+
+        let (decoder, channel) = match topics.entry(topic.clone()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let image_topic = topic.strip_suffix("_video").unwrap_or(topic);
+                if !opts.silent {
+                    status!(opts, "Decoding {} to {}", topic, image_topic);
+                }
+                let decoder = openh264::decoder::Decoder::new()
+                    .map_err(|e| anyhow::anyhow!("Couldn't create an H.264 decoder for {}: {:#}", topic, e))?;
+                entry.insert((decoder, video_channel(image_topic, &image_schema)))
+            }
+        };
+        let rgb8 = match decoder.decode(&video.data) {
+            Ok(Some(yuv)) => {
+                let (width, height) = yuv.dimension_rgb();
+                let mut pixels = vec![0; width * height * 3];
+                yuv.write_rgb8(&mut pixels);
+                RgbImage::from_raw(width as u32, height as u32, pixels)
+            }
+            Ok(None) => None,
+            Err(e) => {
+                let error = anyhow::anyhow!("Couldn't decode message {} on {}: {:#}", message.sequence, topic, e);
+                let mut log = LogBuffer::default();
+                let pass_through = opts.on_error.recover(opts.silent, &mut log, error)?;
+                log.replay(opts);
+                if pass_through {
+                    out.write(&message)?;
+                    passed_through += 1;
+                }
+                continue;
+            }
+        };
+        // Parameter sets alone, or a stream joined after its keyframe, leave
+        // the decoder without a picture to show yet.
+        let Some(rgb8) = rgb8 else {
+            if !opts.silent {
+                status!(opts, "Message {} on {} produced no frame", message.sequence, topic);
+            }
+            continue;
+        };
+
+        let mut out_msg = foxglove::CompressedImage::CompressedImage::new();
+        out_msg.timestamp = video.timestamp;
+        out_msg.frame_id = video.frame_id;
+        (out_msg.format, out_msg.data) = match format {
+            DecodeFormat::Png => {
+                let mut data = Vec::new();
+                image::DynamicImage::ImageRgb8(rgb8).write_to(&mut Cursor::new(&mut data), image::ImageOutputFormat::Png)?;
+                ("png".to_string(), data)
+            }
+            DecodeFormat::Jpeg => ("jpeg".to_string(), frame::encode_jpeg(&rgb8, opts.jpeg_quality)?),
+        };
+        out.write(&mcap::Message {
+            channel: channel.clone(),
+            sequence: message.sequence,
+            log_time: message.log_time,
+            publish_time: message.publish_time,
+            data: Cow::from(out_msg.write_to_bytes()?),
+        })?;
+        decoded_frames += 1;
+    }
+    out.finish()?;
+
+    if !opts.silent {
+        status!(
+            opts,
+            "Decoded {} frames from {} video topics and copied {} other messages into {}",
+            decoded_frames,
+            topics.len(),
+            passed_through,
+            output_path
+        );
+    }
+    Ok(())
+}
+
+/// Bytes of keyframe thumbnails held before they are attached.
+const THUMBNAIL_BUFFER_BYTES: usize = 4 << 20;
+
+/// Keyframe thumbnails waiting to be attached. Attachments end the current
+/// chunk, so they are held back and attached together, once they add up to
+/// `limit` bytes and when the output is finished.
+struct ThumbnailBuffer {
+    pending: Vec<mcap::Attachment<'static>>,
+    bytes: usize,
+    limit: usize,
+}
+
+impl ThumbnailBuffer {
+    fn new(limit: usize) -> Self {
+        Self {
+            pending: Vec::new(),
+            bytes: 0,
+            limit,
+        }
+    }
+
+    fn push<W: std::io::Write + std::io::Seek>(
+        &mut self,
+        out: &mut mcap::Writer<W>,
+        thumb: mcap::Attachment<'static>,
+    ) -> Result<()> {
+        self.bytes += thumb.data.len();
+        self.pending.push(thumb);
+        if self.bytes >= self.limit {
+            self.flush(out)?;
+        }
+        Ok(())
+    }
+
+    fn flush<W: std::io::Write + std::io::Seek>(&mut self, out: &mut mcap::Writer<W>) -> Result<()> {
+        for thumb in self.pending.drain(..) {
+            out.attach(&thumb)?;
+        }
+        self.bytes = 0;
+        Ok(())
+    }
+}
+
+/// Writes everything still pending for an output file and finishes it: buffered
+/// messages, keyframe thumbnails and the optional Foxglove layout.
+fn finish_output<'a, W: std::io::Write + std::io::Seek>(
+    out: &mut mcap::Writer<'a, W>,
+    queue: &mut reorder::OutputQueue<'a>,
+    thumbs: &mut ThumbnailBuffer,
+    layout_topics: Option<&HashMap<String, Arc<mcap::Channel>>>,
+) -> Result<()> {
+    queue.flush(out)?;
+    thumbs.flush(out)?;
+    if let Some(topics) = layout_topics {
+        out.write_metadata(&layout::metadata(topics.keys()))?;
+    }
+    out.finish()?;
+    Ok(())
+}
+
+/// Encodes every CompressedImage and RawImage topic, sorted by name, into one MP4
+/// played in sequence, each topic introduced by a one-second title card. The input is read once per
+/// topic. Frames are letterboxed to the size of the first topic's first frame.
+pub fn concat_mp4(mapped: &[u8], path: &str, opts: &ConvertOptions) -> Result<()> {
+    let ConvertOptions {
+        create_dirs,
+        silent,
+        ref source_encodings,
+        ref external_schema,
+        keyframe_interval,
+        encoder: ref encoder_settings,
+        ..
+    } = *opts;
+    /// A frame waiting for the next frame's log_time to know its duration.
+    struct Pending {
+        data: Vec<u8>,
+        keyframe: bool,
+        log_time: u64,
+    }
+
+    const DEFAULT_DURATION: u32 = mp4::TIMESCALE / 30;
+    let to_ticks = |ns: u64| (ns * u64::from(mp4::TIMESCALE) / 1_000_000_000).clamp(1, u64::from(u32::MAX)) as u32;
+
+    let topics: Vec<String> = counts::TopicCounts::read(mapped)?
+        .topics
+        .into_iter()
+        .filter(|(_, count)| is_convertible_topic(count, source_encodings))
+        .map(|(topic, _)| topic)
+        .collect();
+
+    // Created from the first decoded frame, which fixes the output size.
+    let mut output: Option<(mp4::Mp4Writer, h264::H264Encoder, u32, u32)> = None;
+    let mut frames_written = 0u64;
+    let mut descriptors = DescriptorCache::new(external_schema.as_ref());
+    for topic in &topics {
+        let mut pending: Option<Pending> = None;
+        let mut last_duration = DEFAULT_DURATION;
+        let mut raw_warned = false;
+        for message in mcap::MessageStream::new(mapped)? {
+            let message = message?;
+            if &message.channel.topic != topic {
+                continue;
+            }
+            let Some(schema) = message.channel.schema.as_ref().filter(|s| is_convertible(s, source_encodings)) else {
+                continue;
+            };
+            // The per-message lines parse_image logs are for the MCAP conversion.
+            let (job, _) = parse_image(&message, schema, &mut descriptors, opts, &mut LogBuffer::default())?;
+            if let Some(raw) = job.raw.as_ref().filter(|raw| !frame::is_supported_raw_encoding(&raw.encoding)) {
+                if !raw_warned {
+                    warning!(opts, "{} has RawImage encoding {:?}, which can't be converted; leaving it out of {}", topic, raw.encoding, path);
+                    raw_warned = true;
+                }
+                continue;
+            }
+            let rgb8 = match &job.raw {
+                Some(raw) => frame::decode_raw(&raw.encoding, raw.width, raw.height, raw.step, &job.image).map(|(rgb8, _)| rgb8),
+                None => ImageReader::new(Cursor::new(&job.image))
+                    .with_guessed_format()
+                    .expect("Cursor io never fails")
+                    .decode()
+                    .map(|image| image.to_rgb8())
+                    .map_err(anyhow::Error::from),
+            }
+            .map_err(|e| anyhow::anyhow!("Couldn't decode image in message {} on {}: {:#}", message.sequence, topic, e))?;
+
+            if output.is_none() {
+                let canvas = frame::pad_for_encoder(&RgbImage::new(rgb8.width(), rgb8.height()));
+                let (width, height) = canvas.dimensions();
+                let file = create_output_file(path, create_dirs)?;
+                if !silent {
+                    status!(opts, "Encoding {} at {} bps", path, encoder_settings.bitrate_bps(width, height));
+                }
+                let mut encoder = new_encoder(width, height, encoder_settings, keyframe_interval)?;
+                // The MP4 track header holds a single SPS/PPS pair, so every IDR must reuse its IDs.
+                encoder.reconfigure(|params| params.eSpsPpsIdStrategy = CONSTANT_ID)?;
+                output = Some((mp4::Mp4Writer::create(file, width, height)?, encoder, width, height));
+            }
+            let (writer, encoder, width, height) = output.as_mut().unwrap();
+
+            let mut encode = |img: &RgbImage, keyframe: bool| -> Result<(Vec<u8>, bool)> {
+                if keyframe {
+                    encoder.force_keyframe();
+                }
+                let yuv = YUVBuffer::with_rgb(*width as usize, *height as usize, img);
+                let encoded = encoder.encode(&yuv)?;
+                Ok((encoded.data, encoded.frame_type == FrameType::IDR))
+            };
+
+            let starts_topic = pending.is_none();
+            if starts_topic {
+                let (data, keyframe) = encode(&title::card(topic, *width, *height), true)?;
+                writer.write_sample(&data, keyframe, mp4::TIMESCALE)?;
+            }
+            let (data, keyframe) = encode(&frame::letterbox(&rgb8, *width, *height), starts_topic)?;
+            if data.is_empty() {
+                continue;
+            }
+            if let Some(prev) = pending.take() {
+                last_duration = to_ticks(message.log_time.saturating_sub(prev.log_time));
+                writer.write_sample(&prev.data, prev.keyframe, last_duration)?;
+            }
+            pending = Some(Pending { data, keyframe, log_time: message.log_time });
+            frames_written += 1;
+        }
+        if let (Some(prev), Some((writer, ..))) = (pending, output.as_mut()) {
+            writer.write_sample(&prev.data, prev.keyframe, last_duration)?;
+        }
+    }
+
+    let Some((writer, ..)) = output else {
+        anyhow::bail!("No image frames to write to {}", path);
+    };
+    writer.finish()?;
+    if !silent {
+        status!(opts, "Wrote {} frames from {} topics to {}", frames_written, topics.len(), path);
+    }
+    Ok(())
+}
+
+/// Explains how much of the input made it into the output when `--time-budget` cut a run short.
+fn report_budget_stop(
+    mapped: &[u8],
+    budget: std::time::Duration,
+    messages_read: u64,
+    latest_log_time: Option<u64>,
+    opts: &ConvertOptions,
+) {
+    let stats = mcap::Summary::read(mapped).ok().flatten().and_then(|s| s.stats);
+    let coverage = match (&stats, latest_log_time) {
+        (Some(stats), Some(latest)) if stats.message_end_time > stats.message_start_time => {
+            let covered = latest.saturating_sub(stats.message_start_time) as f64;
+            let total = (stats.message_end_time - stats.message_start_time) as f64;
+            format!(", {:.1}% of the recording's time range", 100.0 * covered / total)
+        }
+        _ => String::new(),
+    };
+    let total_messages = stats
+        .map(|s| format!(" of {}", s.message_count))
+        .unwrap_or_default();
+    warning!(
+        opts,
+        "Time budget of {:.1}s reached: stopped after {}{} messages{}. The output is complete up to that point.",
+        budget.as_secs_f64(),
+        messages_read,
+        total_messages,
+        coverage
+    );
+}
+
+/// Name of the video topic written for the image topic `topic`: its
+/// `--topic-rename` target, or `<topic>_video`.
+fn output_topic(topic: &str, renames: &HashMap<String, String>) -> String {
+    renames.get(topic).cloned().unwrap_or_else(|| format!("{}_video", topic))
+}
+
+/// Checks every `--topic-rename` source is an image topic of the input that
+/// will be converted and, unless merging is allowed, that no two input topics
+/// end up on the same output topic.
+fn check_topic_renames(
+    mapped: &[u8],
+    renames: &HashMap<String, String>,
+    allow_merge: bool,
+    source_encodings: &[String],
+) -> Result<()> {
+    let counts = counts::TopicCounts::read(mapped)?;
+    let is_image = |count: &counts::TopicCount| is_convertible_topic(count, source_encodings);
+    for old in renames.keys() {
+        if !counts.topics.get(old).is_some_and(is_image) {
+            anyhow::bail!(
+                "--topic-rename source {} is not an image topic of the input with one of the --source-encodings",
+                old
+            );
+        }
+    }
+    if allow_merge {
+        return Ok(());
+    }
+
+    // Passed-through topics keep their names, so they can collide too.
+    let mut written: HashMap<String, &str> = HashMap::new();
+    for (topic, count) in &counts.topics {
+        let out = if is_image(count) { output_topic(topic, renames) } else { topic.clone() };
+        if let Some(other) = written.insert(out.clone(), topic) {
+            anyhow::bail!(
+                "Topics {} and {} would both be written to {}. Use --allow-topic-merge to combine them",
+                other,
+                topic,
+                out
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Logs every topic of the input with its schema, message count and time range.
+pub fn list_topics(mapped: &[u8], input_path: &str, opts: &ConvertOptions) -> Result<()> {
+    let counts = counts::TopicCounts::read(mapped)?;
+    status!(
+        opts,
+        "{}: {} messages on {} topics ({})",
+        input_path,
+        counts.total_messages(),
+        counts.topics.len(),
+        if counts.from_summary { "from summary" } else { "scanned" }
+    );
+    let width = counts.topics.keys().map(|t| t.len()).max().unwrap_or(0);
+    for (topic, count) in &counts.topics {
+        let range = count
+            .time_range
+            .map(|(start, end)| format!("{} - {}", timing::format_time(start), timing::format_time(end)))
+            .unwrap_or_else(|| "no messages".to_string());
+        status!(
+            opts,
+            "  {:<width$}  {:>8} msgs  {}  [{}]",
+            topic,
+            count.message_count,
+            range,
+            count.schema.as_deref().unwrap_or("no schema"),
+            width = width
+        );
+    }
+    Ok(())
+}
+
+/// Logs the log_time ordering report of the input's image topics, without converting anything.
+pub fn report_input_timing(mapped: &[u8], opts: &ConvertOptions) -> Result<()> {
+    status!(opts, "{}", scan_timing(mapped, &opts.source_encodings)?.report("input").trim_end());
+    Ok(())
+}
+
+/// Messages read ahead of the writer while their images are converted. It bounds
+/// memory while leaving every topic's worker something to do.
+const MAX_PENDING_MESSAGES: usize = 64;
+
+/// A message read from the input, waiting to be written in input order.
+struct PendingMessage<'a> {
+    message: mcap::Message<'a>,
+    log: LogBuffer,
+    conversion: Conversion,
+    /// The output is split into a new segment before this message.
+    starts_segment: bool,
+}
+
+/// What becomes of a pending message.
+enum Conversion {
+    /// Written as-is.
+    Copy,
+    Image(PendingImage),
+    /// An image message that couldn't be parsed, dropped or copied as-is by `--on-error`.
+    Failed { pass_through: bool },
+    /// An image message that couldn't be parsed, stopping the conversion once
+    /// everything before it is written.
+    Fatal(anyhow::Error),
+}
+
+struct PendingImage {
+    /// Index of the worker whose next result belongs to this message.
+    worker: usize,
+    video_topic: String,
+    /// Source format recorded by --emit-frame-meta.
+    source_format: String,
+}
+
+/// Parses an image message into the job for its topic's worker, along with
+/// the source format recorded by --emit-frame-meta.
+fn parse_image<'a>(
+    message: &mcap::Message<'a>,
+    schema: &Arc<mcap::Schema<'a>>,
+    descriptors: &mut DescriptorCache<'a>,
+    opts: &ConvertOptions,
+    log: &mut LogBuffer,
+) -> Result<(worker::FrameJob, String)> {
+    let topic = &message.channel.topic;
+    let msg = descriptors.get(schema, topic)?;
+    let parsed = msg
+        .parse_from_bytes(&message.data)
+        .map_err(|e| anyhow::anyhow!("Couldn't parse message {} on {}: {:#}", message.sequence, topic, e))?;
+
+    // Only print the message if not silent
+    if !opts.silent {
+        status!(log, "{:?}", msg);
+    }
+
+    let field = |name: &str| msg.field_by_name(name).map(|f| f.get_singular_field_or_default(parsed.as_ref()));
+    // Trimmed-down schemas may leave out timestamp entirely (as opposed to a
+    // present-but-zero value); the message's log_time stands in for it then.
+    let time = match field("timestamp") {
+        Some(value) => {
+            let bytes = value
+                .to_message()
+                .ok_or_else(|| anyhow::anyhow!("The timestamp field of {} on topic {} isn't a message", schema.name, topic))?
+                .write_to_bytes_dyn()?;
+            protobuf::well_known_types::timestamp::Timestamp::parse_from_bytes(bytes.as_slice())
+                .map_err(|e| anyhow::anyhow!("Couldn't parse the timestamp of message {} on {}: {:#}", message.sequence, topic, e))?
+        }
+        None => {
+            let mut time = protobuf::well_known_types::timestamp::Timestamp::new();
+            time.seconds = (message.log_time / 1_000_000_000) as i64;
+            time.nanos = (message.log_time % 1_000_000_000) as i32;
+            time
+        }
+    };
+
+    let frame_id = field("frame_id").map(|v| v.to_string()).unwrap_or_default();
+    let image = field("data")
+        .ok_or_else(|| anyhow::anyhow!("{} schema on topic {} has no data field", schema.name, topic))?
+        .to_bytes()
+        .ok_or_else(|| anyhow::anyhow!("The data field of {} on topic {} isn't bytes", schema.name, topic))?
+        .to_vec();
+    let raw = (schema.name == RAW_IMAGE_SCHEMA).then(|| {
+        let dimension = |name: &str| field(name).and_then(|v| v.to_u32()).unwrap_or(0);
+        worker::RawLayout {
+            encoding: field("encoding").and_then(|v| v.to_str().map(str::to_string)).unwrap_or_default(),
+            width: dimension("width"),
+            height: dimension("height"),
+            step: dimension("step"),
+        }
+    });
+    // RawImage has a pixel encoding where CompressedImage has a format.
+    let source_format = match opts.emit_frame_meta {
+        true => field("format").or_else(|| field("encoding")).map(|v| v.to_string()).unwrap_or_default(),
+        false => String::new(),
+    };
+    let job = worker::FrameJob {
+        topic: topic.clone(),
+        sequence: message.sequence,
+        log_time: message.log_time,
+        time,
+        frame_id,
+        image,
+        raw,
+    };
+    Ok((job, source_format))
+}
+
+/// Converts the MCAP in `mapped`. `new_output` opens each output, given its
+/// index: 0 for the first and, with `segment_duration`, one more per segment.
+pub fn convert_segments<W: Write + Seek>(
+    mapped: &[u8],
+    mut new_output: impl FnMut(usize) -> Result<W>,
+    opts: &ConvertOptions,
+) -> Result<ConvertStats> {
+    opts.validate()?;
+    let ConvertOptions {
+        silent,
+        create_dirs,
+        ref source_encodings,
+        ref external_schema,
+        copy_schema_records,
+        drop_empty_topics,
+        ref topic_renames,
+        allow_topic_merge,
+        codec,
+        tone,
+        ref frame_index_csv,
+        foxglove_layout,
+        emit_frame_meta,
+        time_budget,
+        segment_duration,
+        reorder_window,
+        fix_publish_time,
+        check_timing,
+        profile_report,
+        ..
+    } = *opts;
+    let write_options = opts.resolved_write_options(mapped)?;
+    let tone_lut = (!tone.is_identity()).then(|| tone.lut());
+    if !topic_renames.is_empty() {
+        check_topic_renames(mapped, topic_renames, allow_topic_merge, source_encodings)?;
+    }
+
+    let run_start = Instant::now();
+    let mut stats = ConvertStats::default();
+    let mut input_timing = timing::TimingLog::default();
+    let mut output_timing = timing::TimingLog::default();
+
+    let output_schema = match codec {
+        Codec::H264 => protobuf_schema(
+            "foxglove.CompressedVideo",
+            foxglove::CompressedVideo::file_descriptor(),
+        ),
+        #[cfg(feature = "raw-codec")]
+        Codec::Raw => protobuf_schema(
+            "foxglove.CompressedVideo",
+            foxglove::CompressedVideo::file_descriptor(),
+        ),
+        Codec::Mjpeg => protobuf_schema(
+            "foxglove.CompressedImage",
+            foxglove::CompressedImage::file_descriptor(),
+        ),
+    };
+
+    // Map of topic -> channel for the topic
+    let mut topic_channels: HashMap<String, Arc<mcap::Channel>> = HashMap::new();
+    // Map of input channel -> schemaless copy used with --copy-schema-records off
+    let mut passthrough_channels: HashMap<mcap::Channel, Arc<mcap::Channel>> = HashMap::new();
+    // Map of video topic -> channel for its --emit-frame-meta companion topic
+    let mut meta_channels: HashMap<String, Arc<mcap::Channel>> = HashMap::new();
+
+    let mut keyframe_thumbs = ThumbnailBuffer::new(THUMBNAIL_BUFFER_BYTES);
+    let mut descriptors = DescriptorCache::new(external_schema.as_ref());
+    // Output video topics of every image topic matched for conversion
+    let mut converted_topics: std::collections::BTreeSet<String> = std::collections::BTreeSet::new();
+    // Frames openh264's rate control chose not to code, per output topic.
+    let mut skipped_frames: std::collections::BTreeMap<String, u64> = std::collections::BTreeMap::new();
+
+    let mut frame_index = match &frame_index_csv {
+        Some(path) => {
+            let mut csv = BufWriter::new(create_output_file(path, create_dirs)?);
+            writeln!(csv, "topic,sequence,log_time,publish_time,bytes,keyframe")?;
+            Some(csv)
+        }
+        None => None,
+    };
+
+    let mut video_mcap = write_options
+        .clone()
+        .create(new_output(0)?)
+        .context("Couldn't create output MCAP")?;
+    let mut segments = 1;
+    // log_time of the first message and index of the time slot the current segment covers
+    let mut segment_origin: Option<u64> = None;
+    let mut segment_slot = 0u64;
+    let mut output = reorder::OutputQueue::new(reorder_window);
+
+    // Every encoded frame is written as soon as its topic's worker returns it (or,
+    // with --reorder-window, once the window has passed), at most
+    // MAX_PENDING_MESSAGES messages are read ahead of the writer and only small
+    // per-topic state is kept across iterations, so memory stays flat no matter
+    // how long the recording is. Keep it that way when adding features: anything
+    // buffering frames must be bounded.
+    let mut profile = profile::Profile::new();
+    let mut stream = mcap::MessageStream::new(mapped)?;
+    let mut messages_read = 0u64;
+    let mut latest_log_time: Option<u64> = None;
+    let mut budget_stop = None;
+    let mut input_error = None;
+    let summaries = std::thread::scope(|scope| -> Result<Vec<(String, worker::TopicSummary)>> {
+        // Map of output video topic -> index of its worker in the vectors below
+        let mut workers: HashMap<String, usize> = HashMap::new();
+        let mut jobs: Vec<mpsc::Sender<worker::Job>> = Vec::new();
+        let mut results: Vec<mpsc::Receiver<Result<worker::FrameOutput>>> = Vec::new();
+        // Per worker, its last message without the data, and that message's
+        // source format and video topic: what a flushed frame is written with.
+        let mut last_images: Vec<(mcap::Message, String, String)> = Vec::new();
+        let mut handles = Vec::new();
+        let mut pending: VecDeque<PendingMessage> = VecDeque::new();
+        let mut input_done = false;
+        loop {
+            // Write finished messages in input order. Wait for the oldest when the
+            // queue is full or the input is done; otherwise take only what is ready.
+            while let Some(entry) = pending.front() {
+                let wait = input_done || pending.len() >= MAX_PENDING_MESSAGES;
+                let converted = match &entry.conversion {
+                    Conversion::Image(image) => {
+                        let received = match wait {
+                            true => results[image.worker].recv().ok(),
+                            false => match results[image.worker].try_recv() {
+                                Ok(result) => Some(result),
+                                Err(mpsc::TryRecvError::Empty) => break,
+                                Err(mpsc::TryRecvError::Disconnected) => None,
+                            },
+                        };
+                        Some(received.ok_or_else(|| anyhow::anyhow!("The worker thread for {} stopped", image.video_topic))?)
+                    }
+                    _ => None,
+                };
+                let PendingMessage {
+                    message: full_message,
+                    log,
+                    conversion,
+                    starts_segment,
+                } = pending.pop_front().unwrap();
+
+                if starts_segment {
+                    let write_start = Instant::now();
+                    finish_output(
+                        &mut video_mcap,
+                        &mut output,
+                        &mut keyframe_thumbs,
+                        foxglove_layout.then_some(&topic_channels),
+                    )?;
+                    video_mcap = write_options
+                        .clone()
+                        .create(new_output(segments)?)
+                        .context("Couldn't create output MCAP")?;
+                    profile.since(profile::Stage::Write, write_start);
+                    segments += 1;
+                    if !drop_empty_topics {
+                        for channel in topic_channels.values() {
+                            video_mcap.add_channel(channel)?;
+                        }
+                    }
+                }
+                log.replay(opts);
+
+                let (image, converted) = match (conversion, converted.transpose()?) {
+                    (Conversion::Image(image), Some(converted)) => (image, converted),
+                    (Conversion::Fatal(e), _) => return Err(e),
+                    (Conversion::Failed { pass_through }, _) => {
+                        if pass_through {
+                            output.write(&mut video_mcap, &full_message)?;
+                        }
+                        stats.failed_messages += 1;
+                        continue;
+                    }
+                    _ => {
+                        // Write the message as-is to the output MCAP. The writer registers each
+                        // distinct schema and channel record once, however many messages use it.
+                        let write_start = Instant::now();
+                        output.write(&mut video_mcap, &full_message)?;
+                        profile.since(profile::Stage::Write, write_start);
+                        stats.passed_through_messages += 1;
+                        continue;
+                    }
+                };
+                converted.log.replay(opts);
+                let topic = image.video_topic;
+                if !drop_empty_topics {
+                    if let Entry::Vacant(entry) = topic_channels.entry(topic.clone()) {
+                        let channel = video_channel(entry.key(), &output_schema);
+                        video_mcap.add_channel(&channel)?;
+                        entry.insert(channel);
+                    }
+                }
+
+                for frame in converted.frames {
+                    // The writer adds the channel with its first message, so topics
+                    // that never produce a frame leave no channel behind.
+                    let channel = topic_channels
+                        .entry(topic.clone())
+                        .or_insert_with_key(|key| video_channel(key, &output_schema));
+                    let message = mcap::Message {
+                        channel: channel.clone(),
+                        data: Cow::from(frame.message),
+                        log_time: full_message.log_time + frame.offset_ns,
+                        publish_time: full_message.publish_time + frame.offset_ns,
+                        sequence: full_message.sequence,
+                    };
+                    let write_start = Instant::now();
+                    output.write(&mut video_mcap, &message)?;
+                    profile.since(profile::Stage::Write, write_start);
+                    stats.video_frames += 1;
+                    if check_timing {
+                        output_timing.record(&message.channel.topic, message.log_time);
+                    }
+                    if emit_frame_meta {
+                        let frame_meta = meta::FrameMeta {
+                            source_format: &image.source_format,
+                            source_size: frame.source_size,
+                            encoded_size: frame.encoded_size,
+                            decode_time_us: converted.decode_time.as_micros(),
+                            bytes: frame.bytes,
+                            keyframe: frame.keyframe,
+                            temporal_id: frame.temporal_id,
+                        };
+                        let meta_channel = meta_channels.entry(topic.clone()).or_insert_with(|| {
+                            Arc::new(mcap::Channel {
+                                schema: Some(Arc::new(meta::schema())),
+                                topic: format!("{}_meta", topic),
+                                message_encoding: "json".to_string(),
+                                metadata: std::collections::BTreeMap::new(),
+                            })
+                        });
+                        let write_start = Instant::now();
+                        output.write(&mut video_mcap, &mcap::Message {
+                            channel: meta_channel.clone(),
+                            data: Cow::from(frame_meta.to_json().into_bytes()),
+                            log_time: message.log_time,
+                            publish_time: message.publish_time,
+                            sequence: message.sequence,
+                        })?;
+                        profile.since(profile::Stage::Write, write_start);
+                    }
+                    if let Some(thumbnail) = frame.thumbnail {
+                        let write_start = Instant::now();
+                        keyframe_thumbs.push(
+                            &mut video_mcap,
+                            mcap::Attachment {
+                                log_time: message.log_time,
+                                create_time: message.log_time,
+                                name: format!("{}/keyframe/{}.jpg", topic, message.log_time),
+                                media_type: "image/jpeg".to_string(),
+                                data: Cow::from(thumbnail),
+                            },
+                        )?;
+                        profile.since(profile::Stage::Write, write_start);
+                    }
+                    if let Some(csv) = frame_index.as_mut() {
+                        writeln!(
+                            csv,
+                            "{},{},{},{},{},{}",
+                            csv_field(&message.channel.topic),
+                            message.sequence,
+                            message.log_time,
+                            message.publish_time,
+                            frame.bytes,
+                            frame.keyframe
+                        )?;
+                    }
+                }
+                if converted.outcome == worker::Outcome::PassedThrough {
+                    output.write(&mut video_mcap, &full_message)?;
+                }
+                match converted.outcome {
+                    _ if converted.failed => stats.failed_messages += 1,
+                    worker::Outcome::Transcoded => stats.transcoded_messages += 1,
+                    worker::Outcome::PassedThrough => stats.passed_through_messages += 1,
+                    worker::Outcome::Skipped => stats.skipped_messages += 1,
+                    worker::Outcome::Flushed => {}
+                }
+            }
+            if input_done {
+                if last_images.is_empty() {
+                    break;
+                }
+                // Give every topic the chance to write a frame held back by
+                // rate control, then drain the answers like any other message.
+                for (index, (message, source_format, video_topic)) in last_images.drain(..).enumerate() {
+                    jobs[index]
+                        .send(worker::Job::Flush)
+                        .map_err(|_| anyhow::anyhow!("The worker thread for {} stopped", video_topic))?;
+                    pending.push_back(PendingMessage {
+                        message,
+                        log: LogBuffer::default(),
+                        conversion: Conversion::Image(PendingImage {
+                            worker: index,
+                            video_topic,
+                            source_format,
+                        }),
+                        starts_segment: false,
+                    });
+                }
+                continue;
+            }
+
+            if let Some(budget) = time_budget.filter(|budget| run_start.elapsed() > *budget) {
+                budget_stop = Some(budget);
+                input_done = true;
+                continue;
+            }
+
+            let read_start = Instant::now();
+            let Some(message) = stream.next() else {
+                input_done = true;
+                continue;
+            };
+            let mut full_message = match message {
+                Ok(message) => message,
+                Err(e) => {
+                    input_error = Some(anyhow::anyhow!("Couldn't read the input after {} messages: {:#}", messages_read, e));
+                    input_done = true;
+                    continue;
+                }
+            };
+            profile.since(profile::Stage::Read, read_start);
+            messages_read += 1;
+            latest_log_time = latest_log_time.max(Some(full_message.log_time));
+            // Some writers never fill in publish_time; sorting tools cope better with log_time.
+            if fix_publish_time && full_message.publish_time == 0 {
+                full_message.publish_time = full_message.log_time;
+            }
+            let mut starts_segment = false;
+            if let Some(duration) = segment_duration {
+                let origin = *segment_origin.get_or_insert(full_message.log_time);
+                let slot = full_message.log_time.saturating_sub(origin) / duration;
+                if slot > segment_slot {
+                    segment_slot = slot;
+                    starts_segment = true;
+                    // Each segment must be decodable on its own.
+                    for worker in &jobs {
+                        let _ = worker.send(worker::Job::NewSegment);
+                    }
+                }
+            }
+            let mut log = LogBuffer::default();
+            let schema = match &full_message.channel.schema {
+                Some(schema) if is_convertible(schema, source_encodings) => schema.clone(),
+                // For other messages (including schemaless ones), write them as-is
+                _ => {
+                    if !silent {
+                        status!(
+                            log,
+                            "Leaving message as-is: {:?}",
+                            full_message.channel.schema.as_ref().map(|s| s.name.as_str())
+                        );
+                    }
+                    if !copy_schema_records {
+                        let stripped = passthrough_channels
+                            .entry(full_message.channel.as_ref().clone())
+                            .or_insert_with_key(|channel| {
+                                Arc::new(mcap::Channel {
+                                    schema: None,
+                                    ..channel.clone()
+                                })
+                            });
+                        full_message.channel = stripped.clone();
+                    }
+                    pending.push_back(PendingMessage {
+                        message: full_message,
+                        log,
+                        conversion: Conversion::Copy,
+                        starts_segment,
+                    });
+                    continue;
+                }
+            };
+
+            if check_timing {
+                input_timing.record(&full_message.channel.topic, full_message.log_time);
+            }
+
+            let video_topic = output_topic(&full_message.channel.topic, topic_renames);
+            converted_topics.insert(video_topic.clone());
+
+            let schema_start = Instant::now();
+            let parsed = parse_image(&full_message, &schema, &mut descriptors, opts, &mut log);
+            profile.since(profile::Stage::Schema, schema_start);
+            let (job, source_format) = match parsed {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    let conversion = match opts.on_error.recover(silent, &mut log, e) {
+                        Ok(pass_through) => Conversion::Failed { pass_through },
+                        Err(e) => {
+                            input_done = true;
+                            Conversion::Fatal(e)
+                        }
+                    };
+                    pending.push_back(PendingMessage {
+                        message: full_message,
+                        log,
+                        conversion,
+                        starts_segment,
+                    });
+                    continue;
+                }
+            };
+
+            let index = *workers.entry(video_topic.clone()).or_insert_with_key(|topic| {
+                let (job_sender, job_receiver) = mpsc::channel();
+                let (result_sender, result_receiver) = mpsc::channel();
+                let topic = topic.clone();
+                handles.push((
+                    topic.clone(),
+                    scope.spawn(move || worker::TopicWorker::new(opts, tone_lut, topic).run(job_receiver, result_sender)),
+                ));
+                jobs.push(job_sender);
+                results.push(result_receiver);
+                jobs.len() - 1
+            });
+            let last_image = (
+                mcap::Message {
+                    channel: full_message.channel.clone(),
+                    data: Cow::Borrowed(&[]),
+                    ..full_message
+                },
+                source_format.clone(),
+                video_topic.clone(),
+            );
+            match last_images.get_mut(index) {
+                Some(entry) => *entry = last_image,
+                None => last_images.push(last_image),
+            }
+            jobs[index]
+                .send(worker::Job::Frame(job))
+                .map_err(|_| anyhow::anyhow!("The worker thread for {} stopped", video_topic))?;
+            pending.push_back(PendingMessage {
+                message: full_message,
+                log,
+                conversion: Conversion::Image(PendingImage {
+                    worker: index,
+                    video_topic,
+                    source_format,
+                }),
+                starts_segment,
+            });
+        }
+
+        // Workers finish once their job queues close.
+        drop(jobs);
+        Ok(handles
+            .into_iter()
+            .map(|(topic, handle)| (topic, handle.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic))))
+            .collect())
+    })?;
+    if let Some(budget) = budget_stop {
+        report_budget_stop(mapped, budget, messages_read, latest_log_time, opts);
+        stats.stopped_early = true;
+    }
+    if let Some(e) = input_error {
+        if opts.on_error == OnError::Fail || messages_read == 0 {
+            return Err(e);
+        }
+        warning!(opts, "{:#}. The output is complete up to that point.", e);
+        stats.stopped_early = true;
+    }
+    for (topic, summary) in summaries {
+        profile.merge(&summary.profile);
+        if summary.skipped_frames > 0 {
+            skipped_frames.insert(topic, summary.skipped_frames);
+        }
+    }
+
+    let write_start = Instant::now();
+    finish_output(
+        &mut video_mcap,
+        &mut output,
+        &mut keyframe_thumbs,
+        foxglove_layout.then_some(&topic_channels),
+    )?;
+    profile.since(profile::Stage::Write, write_start);
+    if let Some(mut csv) = frame_index {
+        csv.flush()?;
+    }
+
+    if !silent {
+        for topic in converted_topics.iter().filter(|t| !topic_channels.contains_key(*t)) {
+            status!(opts, "No frames were written for {}; left it out of the output", topic);
+        }
+        for (topic, skipped) in &skipped_frames {
+            status!(opts, "The encoder skipped {} frames of {} to stay within the bitrate", skipped, topic);
+        }
+        status!(
+            opts,
+            "{} image messages transcoded, {} messages passed through, {} skipped, {} failed",
+            stats.transcoded_messages,
+            stats.passed_through_messages,
+            stats.skipped_messages,
+            stats.failed_messages
+        );
+    }
+
+    if check_timing {
+        status!(opts, "{}", input_timing.report("input").trim_end());
+        status!(opts, "{}", output_timing.report("output").trim_end());
+    }
+
+    if profile_report {
+        status!(opts, "{}", profile.report().trim_end());
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_field_quotes_only_when_needed() {
+        assert_eq!(csv_field("/cam0/image"), "/cam0/image");
+        assert_eq!(csv_field("/cam,left"), "\"/cam,left\"");
+        assert_eq!(csv_field("/cam \"a\""), "\"/cam \"\"a\"\"\"");
+        assert_eq!(csv_field("a\nb"), "\"a\nb\"");
+    }
+
+    #[test]
+    fn thumbnails_are_attached_once_the_buffer_is_full() {
+        let mut file = Cursor::new(Vec::new());
+        let mut out = mcap::Writer::new(&mut file).unwrap();
+        let mut thumbs = ThumbnailBuffer::new(1000);
+        for i in 0..10u64 {
+            let thumb = mcap::Attachment {
+                log_time: i,
+                create_time: i,
+                name: format!("{}.jpg", i),
+                media_type: "image/jpeg".to_string(),
+                data: Cow::from(vec![0; 300]),
+            };
+            thumbs.push(&mut out, thumb).unwrap();
+            assert!(thumbs.bytes < 1000);
+        }
+        assert_eq!(thumbs.pending.len(), 2);
+        thumbs.flush(&mut out).unwrap();
+        out.finish().unwrap();
+        drop(out);
+
+        let summary = mcap::Summary::read(file.get_ref()).unwrap().unwrap();
+        let times: Vec<_> = summary.attachment_indexes.iter().map(|a| a.log_time).collect();
+        assert_eq!(times, (0..10).collect::<Vec<_>>());
+    }
+}
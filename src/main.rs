@@ -1,22 +1,27 @@
 use anyhow::{Context, Result};
 use camino::Utf8Path;
-use image::io::Reader as ImageReader;
+use mcap_videoify::{
+    concat_mp4, convert_segments, create_output_file, decode, list_topics, load_external_schema, parse_compression,
+    parse_duration, parse_source_encodings, repack, report_input_timing, strip_indexes, Codec, ConvertOptions, DecodeFormat,
+    ErrorImage, FormatChange, IndexOptions, LogLevel, OnError, Quality, Tonemap, UsageType, MCAP_PROFILES,
+};
 use memmap::Mmap;
-use openh264::encoder::{Encoder, EncoderConfig};
-use openh264::formats::YUVBuffer;
-use protobuf::descriptor::FileDescriptorSet;
-use protobuf::reflect::FileDescriptor;
-use protobuf::Message;
-use std::borrow::Cow;
-use std::collections::HashMap;
-use std::fs::File;
 use std::io::BufWriter;
-use std::io::Cursor;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::{env, fs};
 
-mod foxglove {
-    include!(concat!(env!("OUT_DIR"), "/generated_protos/mod.rs"));
+/// Set by `--print-output-path`, which keeps stdout for the produced file paths alone.
+static STATUS_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Prints a progress or report line to stdout, or to stderr with `--print-output-path`.
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if STATUS_TO_STDERR.load(Ordering::Relaxed) {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
 }
 
 fn map_mcap<P: AsRef<Utf8Path>>(p: P) -> Result<Mmap> {
@@ -24,11 +29,183 @@ fn map_mcap<P: AsRef<Utf8Path>>(p: P) -> Result<Mmap> {
     unsafe { Mmap::map(&fd) }.context("Couldn't map MCAP file")
 }
 
+/// Returns the value following the flag at `args[*i]` and advances past both.
+fn take_value(args: &[String], i: &mut usize, flag: &str) -> Result<String> {
+    if *i + 1 < args.len() {
+        let value = args[*i + 1].clone();
+        *i += 2;
+        Ok(value)
+    } else {
+        anyhow::bail!("Missing value for {} argument", flag);
+    }
+}
+
+/// Path of output segment `index` for `--segment-duration`: `out.mcap` becomes `out_000.mcap`, `out_001.mcap`, ...
+fn segment_path(output_path: &str, index: usize) -> String {
+    let path = Utf8Path::new(output_path);
+    let name = match path.extension() {
+        Some(extension) => format!("{}_{:03}.{}", path.file_stem().unwrap_or_default(), index, extension),
+        None => format!("{}_{:03}", path.file_name().unwrap_or_default(), index),
+    };
+    path.with_file_name(name).into_string()
+}
+
+/// Like [`take_value`], parsing the value as a `T`.
+fn take_parsed<T: std::str::FromStr>(args: &[String], i: &mut usize, flag: &str) -> Result<T> {
+    let value = take_value(args, i, flag)?;
+    value
+        .parse::<T>()
+        .map_err(|_| anyhow::anyhow!("Invalid value for {}: {}", flag, value))
+}
+
+/// Like [`take_value`], parsing the value as a duration in nanoseconds
+/// (see [`parse_duration`]).
+fn take_duration(args: &[String], i: &mut usize, flag: &str) -> Result<u64> {
+    let value = take_value(args, i, flag)?;
+    parse_duration(&value).ok_or_else(|| {
+        anyhow::anyhow!(
+            "Invalid value for {}: {}. Expected a duration such as 2, 1.5s, 1500ms or 250us",
+            flag,
+            value
+        )
+    })
+}
+
+/// Parses an `on`/`off` flag value.
+fn take_switch(args: &[String], i: &mut usize, flag: &str) -> Result<bool> {
+    match take_value(args, i, flag)?.as_str() {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        other => anyhow::bail!("Invalid value for {}: {}. Expected on or off", flag, other),
+    }
+}
+
+/// Rewrites the finished MCAP at `path` without the indexes `indexes` turns off.
+fn rewrite_indexes(path: &str, indexes: IndexOptions) -> Result<()> {
+    if indexes.keeps_all() {
+        return Ok(());
+    }
+    let mapped = map_mcap(path)?;
+    let tmp_path = format!("{}.tmp", path);
+    let tmp = fs::File::create(&tmp_path).with_context(|| format!("Couldn't create {}", tmp_path))?;
+    strip_indexes(&mapped, tmp, indexes).map_err(|e| anyhow::anyhow!("Couldn't rewrite the indexes of {}: {:#}", path, e))?;
+    drop(mapped);
+    fs::rename(&tmp_path, path).with_context(|| format!("Couldn't replace {}", path))?;
+    Ok(())
+}
+
+/// Reopens a finished MCAP and reads every message back, cross-checking the
+/// summary statistics and, when it has them, the chunk message indexes against
+/// what was read. Returns the number of messages and channels verified.
+fn validate_output(path: &str, indexes: IndexOptions) -> Result<(u64, usize)> {
+    let mapped = map_mcap(path)?;
+
+    let mut message_count = 0u64;
+    let mut channels = std::collections::HashSet::new();
+    for message in mcap::MessageStream::new(&mapped).context("Output MCAP is unreadable")? {
+        let message = message.with_context(|| {
+            format!("Output MCAP is corrupt after {} messages", message_count)
+        })?;
+        channels.insert(message.channel.topic.clone());
+        message_count += 1;
+    }
+
+    let summary = mcap::Summary::read(&mapped)
+        .context("Output MCAP summary is unreadable")?
+        .ok_or_else(|| anyhow::anyhow!("Output MCAP has no summary section"))?;
+
+    if let Some(stats) = &summary.stats {
+        if stats.message_count != message_count {
+            anyhow::bail!(
+                "Output MCAP statistics report {} messages but {} were read",
+                stats.message_count,
+                message_count
+            );
+        }
+    }
+
+    if !indexes.keeps_all() {
+        return Ok((message_count, channels.len()));
+    }
+    let mut indexed = 0u64;
+    // Chunks holding only schema and channel records have no message index to read.
+    for chunk_index in summary.chunk_indexes.iter().filter(|c| !c.message_index_offsets.is_empty()) {
+        let indexes = summary
+            .read_message_indexes(&mapped, chunk_index)
+            .context("Output MCAP has an unreadable message index")?;
+        indexed += indexes.values().map(|entries| entries.len() as u64).sum::<u64>();
+    }
+    if indexed != message_count {
+        anyhow::bail!(
+            "Output MCAP message indexes list {} messages but {} were read",
+            indexed,
+            message_count
+        );
+    }
+
+    Ok((message_count, channels.len()))
+}
+
 fn get_help_msg() -> String {
     let options = vec![
         ("-i, --input <FILE>", "Input MCAP file path (required)"),
         ("-o, --output <FILE>", "Output MCAP file path (default: compressed_video.mcap)"),
+        ("--topic-rename <OLD>=<NEW>", "Write the video for image topic OLD to topic NEW instead of OLD_video. Repeatable"),
+        ("--allow-topic-merge", "Allow --topic-rename to send several input topics to the same output topic"),
+        ("--create-dirs", "Create missing parent directories of output files"),
+        ("--print-output-path", "On success print only the written file path(s) to stdout; all other output goes to stderr"),
         ("--silent", "Disable verbose output. Errors and build logs will still be printed."),
+        ("--drop-empty-topics <on|off>", "Leave out video channels for image topics that produce no frames (default: on)"),
+        ("--copy-schema-records <on|off>", "Write schema records for passed-through channels (default: on)"),
+        ("--schema <FILE>", "FileDescriptorSet used for CompressedImage channels whose embedded schema is empty or incomplete"),
+        ("--source-encodings <LIST>", "Comma-separated schema encodings to decode as CompressedImage (default: protobuf)"),
+        ("--mcap-profile <PROFILE>", "Header profile of the output MCAP: foxglove, ros1, ros2 or \"\" (default: input's profile)"),
+        ("--compression <zstd|lz4|none>", "Chunk compression for the output MCAP (default: zstd)"),
+        ("--chunk-size <BYTES>", "Target uncompressed chunk size for the output MCAP (default: 786432)"),
+        ("--message-indexes <on|off>", "Write a message index after every chunk of the output MCAP (default: on)"),
+        ("--chunk-indexes <on|off>", "Write chunk indexes to the summary of the output MCAP (default: on)"),
+        ("--repack-only", "Copy all messages verbatim with the above container settings; no re-encoding (lossless)"),
+        ("--decode", "Turn h264 CompressedVideo topics back into CompressedImage topics, dropping the _video suffix; no encoding"),
+        ("--decode-format <png|jpeg>", "Image format written by --decode; jpeg uses --jpeg-quality (default: png)"),
+        ("--expand-animations", "Encode every frame of animated GIF/APNG payloads instead of failing"),
+        ("--on-error <fail|skip|passthrough>", "Stop on an image message that fails to parse, decode or encode, skip it leaving a gap in the video, or copy it unchanged (default: skip)"),
+        ("--error-image <PATH|#RRGGBB>", "Encode this image or solid colour, scaled to the topic's size, in place of undecodable images"),
+        ("--on-format-change <reinit|error|skip>", "Handle a topic switching image format, pixel layout or resolution mid-stream (default: reinit, restarting at a keyframe)"),
+        ("--tonemap <reinhard|aces>", "Tone-map 16-bit/float (HDR) source images to 8-bit SDR instead of truncating them"),
+        ("--preserve-hdr", "Leave 16-bit/float (HDR) source images untouched as CompressedImage instead of encoding them"),
+        ("--small-images <pad|passthrough>", "Handle frames openh264 can't encode (under 16x16 or odd-sized) by padding them or leaving them as-is (default: pad)"),
+        ("--max-width <PIXELS>", "Shrink frames wider than this, keeping the aspect ratio; with --max-height both are hard caps, letterboxed unless --allow-stretch"),
+        ("--max-height <PIXELS>", "Shrink frames taller than this, keeping the aspect ratio; with --max-width both are hard caps, letterboxed unless --allow-stretch"),
+        ("--allow-stretch", "Stretch frames to fill both --max-width and --max-height instead of letterboxing"),
+        ("--gamma <G>", "Gamma correction applied to every frame, > 0 (default: 1)"),
+        ("--brightness <B>", "Brightness offset applied to every frame, -1 to 1 (default: 0)"),
+        ("--contrast <C>", "Contrast scale around mid-grey applied to every frame, >= 0 (default: 1)"),
+        ("--palette <N>", "Reduce frames to a fixed palette of N colours (2-256) per topic before encoding. Lossy; for diagrams and maps"),
+        ("--codec <h264|mjpeg>", "Output codec. mjpeg writes every frame as a JPEG CompressedImage (default: h264)"),
+        ("--jpeg-quality <1-100>", "JPEG quality used by --codec mjpeg (default: 85)"),
+        ("--temporal-layers <N>", "Encode N temporal SVC layers (1-4) so lower framerates can be extracted (default: 1)"),
+        ("--usage-type <camera|screen|realtime>", "openh264 usage type: camera footage, screen content, or camera with lower per-frame encode cost (default: camera)"),
+        ("--bitrate <BPS>", "Target H.264 bitrate in bits per second for every topic, assuming 30 fps; overrides --quality"),
+        ("--quality <low|medium|high>", "Target H.264 bitrate scaled to each topic's frame size: 0.05, 0.1 or 0.2 bits per pixel at 30 fps"),
+        ("--allow-frame-skip", "Let H.264 rate control drop frames to hold the bitrate; a dropped last frame is encoded again at the end"),
+        ("--yuv-alignment <BYTES>", "Pad YUV plane rows to a multiple of this many bytes for backends that need aligned strides (default: tightly packed)"),
+        ("--keyframe-interval <N>", "Force a keyframe every N frames per topic and set the encoder's IDR period to N (default: 0, encoder's choice)"),
+        ("--only-keyframes-to-mcap", "Write only keyframes, producing a sparse preview stream (requires --keyframe-interval)"),
+        ("--max-gop-bytes <BYTES>", "Force a keyframe once a topic emits this many bytes since its last keyframe"),
+        ("--concat-mp4 <FILE>", "Write every image topic, sorted by name, into one MP4 played in sequence with title cards. Without --output only the MP4 is written"),
+        ("--frame-index-csv <FILE>", "Write a CSV listing every output video frame (topic, sequence, times, size, keyframe)"),
+        ("--emit-frame-meta", "Write a JSON <topic>_video_meta message (sizes, decode time, source format) per frame"),
+        ("--embed-source-ref", "Embed the source topic, sequence and log_time in every encoded frame (H.264 SEI or JPEG comment)"),
+        ("--embed-keyframe-thumbs", "Attach a small JPEG thumbnail of every keyframe to the output MCAP"),
+        ("--foxglove-layout", "Embed a multi-camera Foxglove layout for the produced video topics as metadata"),
+        ("--fix-publish-time", "Replace a zero publish_time with log_time on every written message (off by default)"),
+        ("--reorder-window <DURATION>", "Buffer output messages and write them sorted by log_time within this window, interleaving video with pass-through data"),
+        ("--segment-duration <DURATION>", "Split the output into numbered MCAPs of this much log_time each, every one starting on a keyframe"),
+        ("--time-budget <DURATION>", "Stop converting after this much wall-clock time and finalize a partial output"),
+        ("--validate-output", "Re-read the written MCAP and check it against its indexes (doubles I/O)"),
+        ("--profile-report", "Print the time spent in each pipeline stage at the end of the run"),
+        ("--list-topics", "Print every input topic with its message count and time range, then exit"),
+        ("--check-timing", "Report backwards jumps, gaps and duplicate log_times per image topic. Without --output only the report is produced"),
         ("--warm-up", "Warm up the Rust environment and exit (for CI/Docker)"),
         ("-h, --help", "Show this help message"),
     ];
@@ -48,6 +225,8 @@ fn get_help_msg() -> String {
     for (opt, desc) in options {
         help_msg.push_str(&format!("  {:<width$}  {}\n", opt, desc, width = max_option_len));
     }
+    help_msg.push_str("\n  <DURATION> is a number with an optional unit: ns, us, ms, s, m or h (default: s),\n");
+    help_msg.push_str("  e.g. 2, 1.5s or 1500ms.\n");
 
     help_msg.push_str("\nDescription:\n");
     help_msg.push_str("  This tool processes MCAP files containing image data and converts them to\n");
@@ -67,30 +246,305 @@ fn read_it(output_path: &str) -> Result<()> {
     let args: Vec<String> = env::args().collect();
     let mut input_path = None;
     let mut output_path = output_path.to_string();
-    let mut silent = false;
+    let mut output_given = false;
+    let mut print_output_path = false;
     let mut warmup = false;
+    let mut repack_only = false;
+    let mut list_only = false;
+    let mut decode_format: Option<DecodeFormat> = None;
+    let mut decode_only = false;
+    let mut validate = false;
+    let mut indexes = IndexOptions::default();
+    let mut opts = ConvertOptions::default();
+    let mut concat_mp4_path: Option<String> = None;
 
     let mut i = 1;
     while i < args.len() {
         match args[i].as_str() {
             "--input" | "-i" => {
-                if i + 1 < args.len() {
-                    input_path = Some(args[i + 1].clone());
-                    i += 2;
-                } else {
-                    anyhow::bail!("Missing value for --input/-i argument");
-                }
+                input_path = Some(take_value(&args, &mut i, "--input/-i")?);
             }
             "--output" | "-o" => {
-                if i + 1 < args.len() {
-                    output_path = args[i + 1].clone();
-                    i += 2;
-                } else {
-                    anyhow::bail!("Missing value for --output/-o argument");
+                output_path = take_value(&args, &mut i, "--output/-o")?;
+                output_given = true;
+            }
+            "--topic-rename" => {
+                let value = take_value(&args, &mut i, "--topic-rename")?;
+                let Some((old, new)) = value.split_once('=').filter(|(old, new)| !old.is_empty() && !new.is_empty()) else {
+                    anyhow::bail!("Invalid value for --topic-rename: {}. Expected <OLD>=<NEW>", value);
+                };
+                if opts.topic_renames.insert(old.to_string(), new.to_string()).is_some() {
+                    anyhow::bail!("--topic-rename given twice for {}", old);
+                }
+            }
+            "--allow-topic-merge" => {
+                opts.allow_topic_merge = true;
+                i += 1;
+            }
+            "--create-dirs" => {
+                opts.create_dirs = true;
+                i += 1;
+            }
+            "--drop-empty-topics" => {
+                opts.drop_empty_topics = take_switch(&args, &mut i, "--drop-empty-topics")?;
+            }
+            "--copy-schema-records" => {
+                opts.copy_schema_records = take_switch(&args, &mut i, "--copy-schema-records")?;
+            }
+            "--schema" => {
+                opts.external_schema = Some(load_external_schema(&take_value(&args, &mut i, "--schema")?)?);
+            }
+            "--source-encodings" => {
+                opts.source_encodings = parse_source_encodings(&take_value(&args, &mut i, "--source-encodings")?)?;
+            }
+            "--mcap-profile" => {
+                let value = take_value(&args, &mut i, "--mcap-profile")?;
+                if !MCAP_PROFILES.contains(&value.as_str()) {
+                    anyhow::bail!(
+                        "Invalid value for --mcap-profile: {}. Expected foxglove, ros1, ros2 or \"\"",
+                        value
+                    );
+                }
+                opts.mcap_profile = Some(value);
+            }
+            "--compression" => {
+                let value = take_value(&args, &mut i, "--compression")?;
+                opts.write_options = opts.write_options.compression(parse_compression(&value)?);
+            }
+            "--chunk-size" => {
+                opts.write_options = opts.write_options.chunk_size(Some(take_parsed(&args, &mut i, "--chunk-size")?));
+            }
+            "--message-indexes" => {
+                indexes.message_indexes = take_switch(&args, &mut i, "--message-indexes")?;
+            }
+            "--chunk-indexes" => {
+                indexes.chunk_indexes = take_switch(&args, &mut i, "--chunk-indexes")?;
+            }
+            "--codec" => {
+                opts.codec = match take_value(&args, &mut i, "--codec")?.as_str() {
+                    "h264" => Codec::H264,
+                    "mjpeg" => Codec::Mjpeg,
+                    #[cfg(feature = "raw-codec")]
+                    "raw" => Codec::Raw,
+                    #[cfg(feature = "raw-codec")]
+                    other => anyhow::bail!("Invalid value for --codec: {}. Expected h264, mjpeg or raw", other),
+                    #[cfg(not(feature = "raw-codec"))]
+                    other => anyhow::bail!("Invalid value for --codec: {}. Expected h264 or mjpeg", other),
+                };
+            }
+            "--jpeg-quality" => {
+                opts.jpeg_quality = take_parsed(&args, &mut i, "--jpeg-quality")?;
+                if !(1..=100).contains(&opts.jpeg_quality) {
+                    anyhow::bail!("--jpeg-quality must be between 1 and 100");
+                }
+            }
+            "--temporal-layers" => {
+                opts.encoder.temporal_layers = take_parsed(&args, &mut i, "--temporal-layers")?;
+                if !(1..=4).contains(&opts.encoder.temporal_layers) {
+                    anyhow::bail!("--temporal-layers must be between 1 and 4");
+                }
+            }
+            "--usage-type" => {
+                opts.encoder.usage_type = match take_value(&args, &mut i, "--usage-type")?.as_str() {
+                    "camera" => UsageType::Camera,
+                    "screen" => UsageType::Screen,
+                    "realtime" => UsageType::Realtime,
+                    other => anyhow::bail!("Invalid value for --usage-type: {}. Expected camera, screen or realtime", other),
+                };
+            }
+            "--bitrate" => {
+                let bitrate: u32 = take_parsed(&args, &mut i, "--bitrate")?;
+                if !(1..=i32::MAX as u32).contains(&bitrate) {
+                    anyhow::bail!("--bitrate must be between 1 and {}", i32::MAX);
+                }
+                opts.encoder.bitrate = Some(bitrate);
+            }
+            "--quality" => {
+                opts.encoder.quality = match take_value(&args, &mut i, "--quality")?.as_str() {
+                    "low" => Some(Quality::Low),
+                    "medium" => Some(Quality::Medium),
+                    "high" => Some(Quality::High),
+                    other => anyhow::bail!("Invalid value for --quality: {}. Expected low, medium or high", other),
+                };
+            }
+            "--allow-frame-skip" => {
+                opts.encoder.frame_skip = true;
+                i += 1;
+            }
+            "--yuv-alignment" => {
+                let alignment: usize = take_parsed(&args, &mut i, "--yuv-alignment")?;
+                if !alignment.is_power_of_two() || alignment > 4096 {
+                    anyhow::bail!("--yuv-alignment must be a power of two up to 4096");
+                }
+                opts.yuv_alignment = Some(alignment);
+            }
+            "--keyframe-interval" => {
+                opts.keyframe_interval = take_parsed(&args, &mut i, "--keyframe-interval")?;
+            }
+            "--only-keyframes-to-mcap" => {
+                opts.only_keyframes = true;
+                i += 1;
+            }
+            "--max-gop-bytes" => {
+                opts.max_gop_bytes = Some(take_parsed(&args, &mut i, "--max-gop-bytes")?);
+            }
+            "--max-width" => {
+                opts.size_limit.max_width = Some(take_parsed(&args, &mut i, "--max-width")?);
+            }
+            "--max-height" => {
+                opts.size_limit.max_height = Some(take_parsed(&args, &mut i, "--max-height")?);
+            }
+            "--gamma" => {
+                opts.tone.gamma = take_parsed(&args, &mut i, "--gamma")?;
+                if !(opts.tone.gamma > 0.0 && opts.tone.gamma.is_finite()) {
+                    anyhow::bail!("--gamma must be greater than 0");
+                }
+            }
+            "--brightness" => {
+                opts.tone.brightness = take_parsed(&args, &mut i, "--brightness")?;
+                if !(-1.0..=1.0).contains(&opts.tone.brightness) {
+                    anyhow::bail!("--brightness must be between -1 and 1");
                 }
             }
+            "--contrast" => {
+                opts.tone.contrast = take_parsed(&args, &mut i, "--contrast")?;
+                if !(opts.tone.contrast >= 0.0 && opts.tone.contrast.is_finite()) {
+                    anyhow::bail!("--contrast must be 0 or greater");
+                }
+            }
+            "--palette" => {
+                let size: usize = take_parsed(&args, &mut i, "--palette")?;
+                if !(2..=256).contains(&size) {
+                    anyhow::bail!("--palette must be between 2 and 256");
+                }
+                opts.palette_size = Some(size);
+            }
+            "--on-error" => {
+                opts.on_error = match take_value(&args, &mut i, "--on-error")?.as_str() {
+                    "fail" => OnError::Fail,
+                    "skip" => OnError::Skip,
+                    "passthrough" => OnError::PassThrough,
+                    other => anyhow::bail!("Invalid value for --on-error: {}. Expected fail, skip or passthrough", other),
+                };
+            }
+            "--error-image" => {
+                opts.error_image = Some(ErrorImage::parse(&take_value(&args, &mut i, "--error-image")?)?);
+            }
+            "--on-format-change" => {
+                opts.on_format_change = match take_value(&args, &mut i, "--on-format-change")?.as_str() {
+                    "reinit" => FormatChange::Reinit,
+                    "error" => FormatChange::Error,
+                    "skip" => FormatChange::Skip,
+                    other => anyhow::bail!("Invalid value for --on-format-change: {}. Expected reinit, error or skip", other),
+                };
+            }
+            "--tonemap" => {
+                opts.tonemap = Some(match take_value(&args, &mut i, "--tonemap")?.as_str() {
+                    "reinhard" => Tonemap::Reinhard,
+                    "aces" => Tonemap::Aces,
+                    other => anyhow::bail!("Invalid value for --tonemap: {}. Expected reinhard or aces", other),
+                });
+            }
+            "--preserve-hdr" => {
+                opts.preserve_hdr = true;
+                i += 1;
+            }
+            "--small-images" => {
+                opts.pass_through_small_images = match take_value(&args, &mut i, "--small-images")?.as_str() {
+                    "pad" => false,
+                    "passthrough" => true,
+                    other => anyhow::bail!("Invalid value for --small-images: {}. Expected pad or passthrough", other),
+                };
+            }
+            "--expand-animations" => {
+                opts.expand_animations = true;
+                i += 1;
+            }
+            "--allow-stretch" => {
+                opts.size_limit.allow_stretch = true;
+                i += 1;
+            }
+            "--concat-mp4" => {
+                concat_mp4_path = Some(take_value(&args, &mut i, "--concat-mp4")?);
+            }
+            "--frame-index-csv" => {
+                opts.frame_index_csv = Some(take_value(&args, &mut i, "--frame-index-csv")?);
+            }
+            "--emit-frame-meta" => {
+                opts.emit_frame_meta = true;
+                i += 1;
+            }
+            "--embed-source-ref" => {
+                opts.embed_source_ref = true;
+                i += 1;
+            }
+            "--embed-keyframe-thumbs" => {
+                opts.embed_keyframe_thumbs = true;
+                i += 1;
+            }
+            "--foxglove-layout" => {
+                opts.foxglove_layout = true;
+                i += 1;
+            }
+            "--repack-only" => {
+                repack_only = true;
+                i += 1;
+            }
+            "--decode" => {
+                decode_only = true;
+                i += 1;
+            }
+            "--decode-format" => {
+                decode_format = Some(match take_value(&args, &mut i, "--decode-format")?.as_str() {
+                    "png" => DecodeFormat::Png,
+                    "jpeg" => DecodeFormat::Jpeg,
+                    other => anyhow::bail!("Invalid value for --decode-format: {}. Expected png or jpeg", other),
+                });
+            }
+            "--print-output-path" => {
+                print_output_path = true;
+                STATUS_TO_STDERR.store(true, Ordering::Relaxed);
+                i += 1;
+            }
             "--silent" => {
-                silent = true;
+                opts.silent = true;
+                i += 1;
+            }
+            "--fix-publish-time" => {
+                opts.fix_publish_time = true;
+                i += 1;
+            }
+            "--validate-output" => {
+                validate = true;
+                i += 1;
+            }
+            "--segment-duration" => {
+                let duration = take_duration(&args, &mut i, "--segment-duration")?;
+                if duration == 0 {
+                    anyhow::bail!("--segment-duration must be positive");
+                }
+                opts.segment_duration = Some(duration);
+            }
+            "--time-budget" => {
+                let duration = take_duration(&args, &mut i, "--time-budget")?;
+                if duration == 0 {
+                    anyhow::bail!("--time-budget must be positive");
+                }
+                opts.time_budget = Some(std::time::Duration::from_nanos(duration));
+            }
+            "--reorder-window" => {
+                opts.reorder_window = Some(take_duration(&args, &mut i, "--reorder-window")?);
+            }
+            "--profile-report" => {
+                opts.profile_report = true;
+                i += 1;
+            }
+            "--list-topics" => {
+                list_only = true;
+                i += 1;
+            }
+            "--check-timing" => {
+                opts.check_timing = true;
                 i += 1;
             }
             "--warm-up" => {
@@ -109,157 +563,103 @@ fn read_it(output_path: &str) -> Result<()> {
 
     // Warm up sequence. Intended for CI & Docker builds.
     if warmup {
-        println!("mcap-videoify and underlying rust environment has been warmed up.");
+        status!("mcap-videoify and underlying rust environment has been warmed up.");
         std::process::exit(0);
     }
 
+    opts.validate()?;
+    if decode_format.is_some() && !decode_only {
+        anyhow::bail!("--decode-format requires --decode");
+    }
+    opts.log = Some(Box::new(|level, line| match level {
+        LogLevel::Status => status!("{}", line),
+        LogLevel::Warning => eprintln!("Warning: {}", line),
+    }));
+
     let input_path = input_path.ok_or_else(|| anyhow::anyhow!("No input file specified. Use --input/-i to specify input file"))?;
     let mapped = map_mcap(&input_path)?;
 
-    let mut set = FileDescriptorSet::new();
-    set.file
-        .push(foxglove::CompressedVideo::file_descriptor().proto().clone());
-    set.file.push(
-        ::protobuf::well_known_types::timestamp::file_descriptor()
-            .proto()
-            .clone(),
-    );
-
-    let cow = Cow::from(set.write_to_bytes().unwrap());
-
-    let compressed_video_schema = mcap::Schema {
-        name: "foxglove.CompressedVideo".to_string(),
-        encoding: "protobuf".to_string(),
-        data: cow.clone(),
-    };
-
-    // Map of topic -> channel for the topic
-    let mut topic_channels: HashMap<String, mcap::Channel> = HashMap::new();
-
-    let mut encoders_by_topic: HashMap<String, Encoder> = HashMap::new();
-
-    let mut video_mcap = mcap::Writer::new(BufWriter::new(
-        File::create(&output_path).unwrap(),
-    ))
-    .unwrap();
+    if list_only {
+        return list_topics(&mapped, &input_path, &opts);
+    }
 
-    for message in mcap::MessageStream::new(&mapped)? {
-        let full_message = message.unwrap();
-        let schema = full_message.channel.schema.as_ref().unwrap().clone();
+    if repack_only {
+        repack(&mapped, &output_path, &opts)?;
+        rewrite_indexes(&output_path, indexes)?;
+        if validate {
+            let (messages, channels) = validate_output(&output_path, indexes)?;
+            status!("Validated {}: {} messages on {} channels", output_path, messages, channels);
+        }
+        if print_output_path {
+            println!("{}", output_path);
+        }
+        return Ok(());
+    }
 
-        // For other messages, write them as-is
-        if schema.name.ne("foxglove.CompressedImage") || schema.encoding.ne("protobuf") {
-            if !silent {
-                println!("Leaving message as-is: {:?}", schema.name);
-            }
-            // Write the message as-is to the output MCAP
-            video_mcap.write(&full_message).unwrap();
-            continue;
+    if decode_only {
+        decode(&mapped, &output_path, decode_format.unwrap_or(DecodeFormat::Png), &opts)?;
+        rewrite_indexes(&output_path, indexes)?;
+        if validate {
+            let (messages, channels) = validate_output(&output_path, indexes)?;
+            status!("Validated {}: {} messages on {} channels", output_path, messages, channels);
         }
+        if print_output_path {
+            println!("{}", output_path);
+        }
+        return Ok(());
+    }
 
-        let set_proto = FileDescriptorSet::parse_from_bytes(&schema.data)?;
-        let descriptors = FileDescriptor::new_dynamic_fds(set_proto.file, &[]).unwrap();
+    if let Some(path) = &concat_mp4_path {
+        concat_mp4(&mapped, path, &opts)?;
+        if print_output_path {
+            println!("{}", path);
+        }
+        if !output_given {
+            return Ok(());
+        }
+    }
 
-        // fixme - why index 1?
-        let msg = descriptors[1]
-            .message_by_full_name(".foxglove.CompressedImage")
-            .unwrap();
+    // Without an explicit output, --check-timing only reports on the input.
+    if opts.check_timing && !output_given {
+        return report_input_timing(&mapped, &opts);
+    }
 
-        let parsed = msg.parse_from_bytes(&full_message.data)?;
+    let mut written_paths = Vec::new();
+    let create_dirs = opts.create_dirs;
+    let segmented = opts.segment_duration.is_some();
+    let stats = convert_segments(
+        &mapped,
+        |index| {
+            let path = if segmented { segment_path(&output_path, index) } else { output_path.clone() };
+            let file = create_output_file(&path, create_dirs)?;
+            written_paths.push(path);
+            Ok(BufWriter::new(file))
+        },
+        &opts,
+    )?;
+    // Under --on-error skip or passthrough a run with some bad images still
+    // succeeds; one where none of the images converted does not.
+    if stats.failed_messages > 0 && stats.transcoded_messages == 0 {
+        anyhow::bail!("None of the {} image messages could be converted", stats.failed_messages);
+    }
 
-        // Only print the message if not silent
-        if !silent {
-            println!("{:?}", msg);
+    for path in &written_paths {
+        rewrite_indexes(path, indexes)?;
+    }
+    if validate {
+        for path in &written_paths {
+            let (messages, channels) = validate_output(path, indexes)?;
+            status!("Validated {}: {} messages on {} channels", path, messages, channels);
         }
-
-        let timestamp = msg
-            .field_by_name("timestamp")
-            .unwrap()
-            .get_singular_field_or_default(parsed.as_ref());
-
-        let frame_id = msg
-            .field_by_name("frame_id")
-            .unwrap()
-            .get_singular_field_or_default(parsed.as_ref());
-
-        let data = msg
-            .field_by_name("data")
-            .unwrap()
-            .get_singular_field_or_default(parsed.as_ref());
-
-        let reader = ImageReader::new(Cursor::new(data.to_bytes().unwrap()))
-            .with_guessed_format()
-            .expect("Cursor io never fails");
-
-        let img = reader.decode()?;
-
-        let rgb8 = &img.to_rgb8();
-
-        let width = usize::try_from(rgb8.width()).unwrap();
-        let height = usize::try_from(rgb8.height()).unwrap();
-
-        let topic = std::format!("{topic}_video", topic = full_message.channel.topic);
-
-        let encoder = encoders_by_topic.entry(topic.clone()).or_insert_with(||{
-            // fixme - command line argument for bitrate
-            let config =
-                EncoderConfig::new(rgb8.width(), rgb8.height()).set_bitrate_bps(10_000_000);
-            
-            return Encoder::with_config(config).unwrap();
-        });
-         
-        let yuv = YUVBuffer::with_rgb(width, height, &rgb8);
-        let bitstream = encoder.encode(&yuv).unwrap();
-
-        let mut out_msg = foxglove::CompressedVideo::CompressedVideo::new();
-
-        let bytes = timestamp
-            .to_message()
-            .unwrap()
-            .write_to_bytes_dyn()
-            .unwrap();
-        let time =
-            protobuf::well_known_types::timestamp::Timestamp::parse_from_bytes(bytes.as_slice())
-                .unwrap();
-        out_msg.timestamp.mut_or_insert_default().seconds = time.seconds;
-        out_msg.timestamp.mut_or_insert_default().nanos = time.nanos;
-
-        out_msg.frame_id = frame_id.to_string();
-        out_msg.format = "h264".to_string();
-        out_msg.data = bitstream.to_vec();
-
-        let out_bytes: Vec<u8> = out_msg.write_to_bytes().unwrap();
-
-        let channel = topic_channels.entry(topic.clone()).or_insert_with_key(|key| {
-            let new_channel = mcap::Channel {
-                schema: Some(Arc::new(compressed_video_schema.to_owned())),
-                topic: key.to_string(),
-                message_encoding: "protobuf".to_string(),
-                metadata: std::collections::BTreeMap::new(),
-            };
-
-            video_mcap
-                .add_channel(&new_channel)
-                .expect("Couldn't write channel");
-
-            return new_channel;
-        });
-
-        let message = mcap::Message {
-            channel: Arc::new(channel.to_owned()),
-            data: Cow::from(out_bytes),
-            log_time: full_message.log_time,
-            publish_time: full_message.publish_time,
-            sequence: full_message.sequence,
-        };
-
-        // fixme - why would out_bytes be 0? if the frame did not change?
-        if out_msg.data.len() > 0 {
-            video_mcap.write(&message).unwrap();
+    }
+    if segmented && !opts.silent {
+        status!("Wrote {} segments: {}", written_paths.len(), written_paths.join(", "));
+    }
+    if print_output_path {
+        for path in &written_paths {
+            println!("{}", path);
         }
     }
-
-    video_mcap.finish().unwrap();
     Ok(())
 }
 
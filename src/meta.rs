@@ -0,0 +1,60 @@
+//! Companion `<topic>_video_meta` messages written by `--emit-frame-meta`.
+
+use crate::json_string;
+use std::borrow::Cow;
+
+pub const SCHEMA_NAME: &str = "mcap_videoify.FrameMeta";
+
+/// JSON Schema describing every frame metadata message.
+const SCHEMA: &str = r#"{
+  "title": "mcap_videoify.FrameMeta",
+  "description": "Per-frame details of a frame written to the matching _video topic",
+  "type": "object",
+  "properties": {
+    "source_format": { "type": "string", "description": "format field of the source CompressedImage" },
+    "source_width": { "type": "integer", "description": "Width of the decoded source image in pixels" },
+    "source_height": { "type": "integer", "description": "Height of the decoded source image in pixels" },
+    "encoded_width": { "type": "integer", "description": "Width of the encoded video frame in pixels" },
+    "encoded_height": { "type": "integer", "description": "Height of the encoded video frame in pixels" },
+    "decode_time_us": { "type": "integer", "description": "Time spent decoding the source image in microseconds" },
+    "bytes": { "type": "integer", "description": "Size of the encoded frame in bytes" },
+    "keyframe": { "type": "boolean", "description": "Whether the encoded frame is an IDR frame" },
+    "temporal_id": { "type": "integer", "description": "Temporal SVC layer of the frame; 0 is the base layer" }
+  }
+}"#;
+
+pub fn schema() -> mcap::Schema<'static> {
+    mcap::Schema {
+        name: SCHEMA_NAME.to_string(),
+        encoding: "jsonschema".to_string(),
+        data: Cow::Borrowed(SCHEMA.as_bytes()),
+    }
+}
+
+/// Details recorded for one output video frame.
+pub struct FrameMeta<'a> {
+    pub source_format: &'a str,
+    pub source_size: (u32, u32),
+    pub encoded_size: (u32, u32),
+    pub decode_time_us: u128,
+    pub bytes: usize,
+    pub keyframe: bool,
+    pub temporal_id: u8,
+}
+
+impl FrameMeta<'_> {
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"source_format\":{},\"source_width\":{},\"source_height\":{},\"encoded_width\":{},\"encoded_height\":{},\"decode_time_us\":{},\"bytes\":{},\"keyframe\":{},\"temporal_id\":{}}}",
+            json_string(self.source_format),
+            self.source_size.0,
+            self.source_size.1,
+            self.encoded_size.0,
+            self.encoded_size.1,
+            self.decode_time_us,
+            self.bytes,
+            self.keyframe,
+            self.temporal_id
+        )
+    }
+}
@@ -0,0 +1,274 @@
+//! Minimal MP4 (ISO BMFF) muxer for a single H.264 video track, used by `--concat-mp4`.
+//!
+//! Samples are streamed into `mdat` as they are encoded and only their sizes,
+//! durations and keyframe flags are kept; `moov` is written after them.
+
+use anyhow::Result;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+
+/// Media timescale, in ticks per second.
+pub const TIMESCALE: u32 = 90_000;
+
+struct Sample {
+    size: u32,
+    duration: u32,
+    keyframe: bool,
+}
+
+/// Writes one H.264 track of fixed `width` x `height` to an MP4 file.
+pub struct Mp4Writer {
+    out: BufWriter<File>,
+    width: u32,
+    height: u32,
+    /// File offset of the `mdat` box header, patched with its size on finish.
+    mdat_offset: u64,
+    mdat_len: u64,
+    sps: Option<Vec<u8>>,
+    pps: Option<Vec<u8>>,
+    samples: Vec<Sample>,
+}
+
+fn mp4_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(body.len() + 8);
+    out.extend_from_slice(&(body.len() as u32 + 8).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(body);
+    out
+}
+
+fn full_box(kind: &[u8; 4], version: u8, flags: u32, body: &[u8]) -> Vec<u8> {
+    let mut full = Vec::with_capacity(body.len() + 4);
+    full.extend_from_slice(&(u32::from(version) << 24 | flags).to_be_bytes());
+    full.extend_from_slice(body);
+    mp4_box(kind, &full)
+}
+
+/// The identity transformation matrix used by `mvhd` and `tkhd`.
+fn unity_matrix() -> Vec<u8> {
+    [0x0001_0000u32, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000]
+        .iter()
+        .flat_map(|v| v.to_be_bytes())
+        .collect()
+}
+
+/// Splits an Annex B byte stream into NAL units, without their start codes.
+fn nal_units(annex_b: &[u8]) -> Vec<&[u8]> {
+    let mut starts = Vec::new();
+    let mut i = 0;
+    while i + 3 <= annex_b.len() {
+        if annex_b[i..i + 3] == [0, 0, 1] {
+            starts.push(i + 3);
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+
+    let mut units = Vec::with_capacity(starts.len());
+    for (n, &start) in starts.iter().enumerate() {
+        let mut end = starts.get(n + 1).map_or(annex_b.len(), |next| next - 3);
+        // A four-byte start code leaves a zero byte before the next three-byte one.
+        while end > start && annex_b[end - 1] == 0 {
+            end -= 1;
+        }
+        if end > start {
+            units.push(&annex_b[start..end]);
+        }
+    }
+    units
+}
+
+impl Mp4Writer {
+    pub fn create(file: File, width: u32, height: u32) -> Result<Self> {
+        let mut out = BufWriter::new(file);
+        let mut ftyp = Vec::new();
+        ftyp.extend_from_slice(b"isom");
+        ftyp.extend_from_slice(&0x200u32.to_be_bytes());
+        ftyp.extend_from_slice(b"isomiso2avc1mp41");
+        out.write_all(&mp4_box(b"ftyp", &ftyp))?;
+
+        let mdat_offset = out.stream_position()?;
+        // 64-bit size, so long exports don't overflow; filled in by finish().
+        out.write_all(&1u32.to_be_bytes())?;
+        out.write_all(b"mdat")?;
+        out.write_all(&0u64.to_be_bytes())?;
+
+        Ok(Self {
+            out,
+            width,
+            height,
+            mdat_offset,
+            mdat_len: 0,
+            sps: None,
+            pps: None,
+            samples: Vec::new(),
+        })
+    }
+
+    /// Appends one encoded frame, given as the encoder's Annex B output.
+    /// Parameter sets are moved into the track header; everything else is
+    /// stored length-prefixed.
+    pub fn write_sample(&mut self, annex_b: &[u8], keyframe: bool, duration: u32) -> Result<()> {
+        let mut size = 0u32;
+        for nal in nal_units(annex_b) {
+            match nal[0] & 0x1f {
+                7 => {
+                    self.sps.get_or_insert_with(|| nal.to_vec());
+                }
+                8 => {
+                    self.pps.get_or_insert_with(|| nal.to_vec());
+                }
+                _ => {
+                    self.out.write_all(&(nal.len() as u32).to_be_bytes())?;
+                    self.out.write_all(nal)?;
+                    size += 4 + nal.len() as u32;
+                }
+            }
+        }
+        self.mdat_len += u64::from(size);
+        self.samples.push(Sample { size, duration, keyframe });
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        let (Some(sps), Some(pps)) = (self.sps.take(), self.pps.take()) else {
+            anyhow::bail!("No H.264 parameter sets were written; the MP4 has no frames");
+        };
+
+        self.out.seek(SeekFrom::Start(self.mdat_offset + 8))?;
+        self.out.write_all(&(self.mdat_len + 16).to_be_bytes())?;
+        self.out.seek(SeekFrom::End(0))?;
+
+        let moov = self.moov(&sps, &pps);
+        self.out.write_all(&moov)?;
+        self.out.flush()?;
+        Ok(())
+    }
+
+    fn moov(&self, sps: &[u8], pps: &[u8]) -> Vec<u8> {
+        let duration: u64 = self.samples.iter().map(|s| u64::from(s.duration)).sum();
+
+        let mut mvhd = Vec::new();
+        mvhd.extend_from_slice(&[0; 16]); // creation and modification time
+        mvhd.extend_from_slice(&TIMESCALE.to_be_bytes());
+        mvhd.extend_from_slice(&duration.to_be_bytes());
+        mvhd.extend_from_slice(&0x0001_0000u32.to_be_bytes()); // rate 1.0
+        mvhd.extend_from_slice(&0x0100u16.to_be_bytes()); // volume 1.0
+        mvhd.extend_from_slice(&[0; 10]);
+        mvhd.extend_from_slice(&unity_matrix());
+        mvhd.extend_from_slice(&[0; 24]);
+        mvhd.extend_from_slice(&2u32.to_be_bytes()); // next track ID
+
+        let mut tkhd = Vec::new();
+        tkhd.extend_from_slice(&[0; 16]);
+        tkhd.extend_from_slice(&1u32.to_be_bytes()); // track ID
+        tkhd.extend_from_slice(&[0; 4]);
+        tkhd.extend_from_slice(&duration.to_be_bytes());
+        tkhd.extend_from_slice(&[0; 16]); // reserved, layer, group, volume
+        tkhd.extend_from_slice(&unity_matrix());
+        tkhd.extend_from_slice(&(self.width << 16).to_be_bytes());
+        tkhd.extend_from_slice(&(self.height << 16).to_be_bytes());
+
+        let mut mdhd = Vec::new();
+        mdhd.extend_from_slice(&[0; 16]);
+        mdhd.extend_from_slice(&TIMESCALE.to_be_bytes());
+        mdhd.extend_from_slice(&duration.to_be_bytes());
+        mdhd.extend_from_slice(&0x55c4u16.to_be_bytes()); // language "und"
+        mdhd.extend_from_slice(&[0; 2]);
+
+        let mut hdlr = vec![0; 4];
+        hdlr.extend_from_slice(b"vide");
+        hdlr.extend_from_slice(&[0; 12]);
+        hdlr.extend_from_slice(b"VideoHandler\0");
+
+        let mut avcc = vec![1, sps[1], sps[2], sps[3], 0xff, 0xe1];
+        avcc.extend_from_slice(&(sps.len() as u16).to_be_bytes());
+        avcc.extend_from_slice(sps);
+        avcc.push(1);
+        avcc.extend_from_slice(&(pps.len() as u16).to_be_bytes());
+        avcc.extend_from_slice(pps);
+
+        let mut avc1 = vec![0; 6];
+        avc1.extend_from_slice(&1u16.to_be_bytes()); // data reference index
+        avc1.extend_from_slice(&[0; 16]);
+        avc1.extend_from_slice(&(self.width as u16).to_be_bytes());
+        avc1.extend_from_slice(&(self.height as u16).to_be_bytes());
+        avc1.extend_from_slice(&0x0048_0000u32.to_be_bytes()); // 72 dpi
+        avc1.extend_from_slice(&0x0048_0000u32.to_be_bytes());
+        avc1.extend_from_slice(&[0; 4]);
+        avc1.extend_from_slice(&1u16.to_be_bytes()); // frames per sample
+        avc1.extend_from_slice(&[0; 32]); // compressor name
+        avc1.extend_from_slice(&0x0018u16.to_be_bytes()); // depth
+        avc1.extend_from_slice(&(-1i16).to_be_bytes());
+        avc1.extend_from_slice(&mp4_box(b"avcC", &avcc));
+
+        let mut stsd = 1u32.to_be_bytes().to_vec();
+        stsd.extend_from_slice(&mp4_box(b"avc1", &avc1));
+
+        // Run-length encoded sample durations.
+        let mut runs: Vec<(u32, u32)> = Vec::new();
+        for sample in &self.samples {
+            match runs.last_mut() {
+                Some((count, duration)) if *duration == sample.duration => *count += 1,
+                _ => runs.push((1, sample.duration)),
+            }
+        }
+        let mut stts = (runs.len() as u32).to_be_bytes().to_vec();
+        for (count, duration) in runs {
+            stts.extend_from_slice(&count.to_be_bytes());
+            stts.extend_from_slice(&duration.to_be_bytes());
+        }
+
+        let keyframes: Vec<u32> = (1..)
+            .zip(&self.samples)
+            .filter(|(_, s)| s.keyframe)
+            .map(|(n, _)| n)
+            .collect();
+        let mut stss = (keyframes.len() as u32).to_be_bytes().to_vec();
+        for n in keyframes {
+            stss.extend_from_slice(&n.to_be_bytes());
+        }
+
+        let mut stsz = vec![0; 4];
+        stsz.extend_from_slice(&(self.samples.len() as u32).to_be_bytes());
+        for sample in &self.samples {
+            stsz.extend_from_slice(&sample.size.to_be_bytes());
+        }
+
+        // Every sample sits in one chunk: the body of mdat.
+        let mut stsc = 1u32.to_be_bytes().to_vec();
+        stsc.extend_from_slice(&1u32.to_be_bytes());
+        stsc.extend_from_slice(&(self.samples.len() as u32).to_be_bytes());
+        stsc.extend_from_slice(&1u32.to_be_bytes());
+        let mut co64 = 1u32.to_be_bytes().to_vec();
+        co64.extend_from_slice(&(self.mdat_offset + 16).to_be_bytes());
+
+        let stbl = [
+            full_box(b"stsd", 0, 0, &stsd),
+            full_box(b"stts", 0, 0, &stts),
+            full_box(b"stss", 0, 0, &stss),
+            full_box(b"stsz", 0, 0, &stsz),
+            full_box(b"stsc", 0, 0, &stsc),
+            full_box(b"co64", 0, 0, &co64),
+        ]
+        .concat();
+
+        let dref = full_box(b"dref", 0, 0, &[&1u32.to_be_bytes()[..], &full_box(b"url ", 0, 1, &[])].concat());
+        let minf = [
+            full_box(b"vmhd", 0, 1, &[0; 8]),
+            mp4_box(b"dinf", &dref),
+            mp4_box(b"stbl", &stbl),
+        ]
+        .concat();
+        let mdia = [
+            full_box(b"mdhd", 1, 0, &mdhd),
+            full_box(b"hdlr", 0, 0, &hdlr),
+            mp4_box(b"minf", &minf),
+        ]
+        .concat();
+        let trak = [full_box(b"tkhd", 1, 3, &tkhd), mp4_box(b"mdia", &mdia)].concat();
+
+        mp4_box(b"moov", &[full_box(b"mvhd", 1, 0, &mvhd), mp4_box(b"trak", &trak)].concat())
+    }
+}
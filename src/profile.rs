@@ -0,0 +1,81 @@
+//! Per-stage timing breakdown printed by `--profile-report`.
+
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+/// A pipeline stage whose time is aggregated separately.
+#[derive(Clone, Copy)]
+pub enum Stage {
+    /// Iterating `MessageStream`, including chunk decompression.
+    Read,
+    /// Parsing CompressedImage schemas and message payloads.
+    Schema,
+    /// Decoding source images.
+    Decode,
+    /// Resizing and adjusting decoded frames.
+    Process,
+    /// Converting RGB frames to YUV.
+    Yuv,
+    /// H.264 encoding.
+    Encode,
+    /// Writing messages to the output MCAP.
+    Write,
+}
+
+const STAGES: [(Stage, &str); 7] = [
+    (Stage::Read, "MCAP read/iterate"),
+    (Stage::Schema, "schema/message parse"),
+    (Stage::Decode, "image decode"),
+    (Stage::Process, "frame processing"),
+    (Stage::Yuv, "RGB -> YUV"),
+    (Stage::Encode, "encode"),
+    (Stage::Write, "MCAP write"),
+];
+
+/// Total time spent in each [`Stage`] over a run.
+pub struct Profile {
+    started: Instant,
+    totals: [Duration; STAGES.len()],
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            totals: [Duration::ZERO; STAGES.len()],
+        }
+    }
+
+    pub fn add(&mut self, stage: Stage, elapsed: Duration) {
+        self.totals[stage as usize] += elapsed;
+    }
+
+    /// Adds the time since `start` to `stage`.
+    pub fn since(&mut self, stage: Stage, start: Instant) {
+        self.add(stage, start.elapsed());
+    }
+
+    /// Adds the totals `other` collected on another thread.
+    pub fn merge(&mut self, other: &Profile) {
+        for (total, other) in self.totals.iter_mut().zip(other.totals) {
+            *total += other;
+        }
+    }
+
+    /// Renders the breakdown as a share of the wall-clock time since [`Profile::new`].
+    /// Stages run on the topic threads are summed over them, so with several
+    /// topics the stages can add up to more than the wall-clock time.
+    pub fn report(&self) -> String {
+        let wall = self.started.elapsed();
+        let percent = |d: Duration| 100.0 * d.as_secs_f64() / wall.as_secs_f64().max(f64::EPSILON);
+
+        let mut out = String::from("Profile report:\n");
+        for ((_, name), total) in STAGES.iter().zip(self.totals) {
+            let _ = writeln!(out, "  {:<22} {:>10.3} s  {:>5.1}%", name, total.as_secs_f64(), percent(total));
+        }
+        let other = wall.saturating_sub(self.totals.iter().sum());
+        let _ = writeln!(out, "  {:<22} {:>10.3} s  {:>5.1}%", "other", other.as_secs_f64(), percent(other));
+        let _ = writeln!(out, "  {:<22} {:>10.3} s", "total", wall.as_secs_f64());
+        out
+    }
+}
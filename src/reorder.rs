@@ -0,0 +1,89 @@
+//! Output buffer used by `--reorder-window` to interleave video and pass-through
+//! messages by `log_time` instead of writing them in processing order.
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::io::{Seek, Write};
+
+/// A buffered message, ordered by `log_time` and then by arrival so equal
+/// times keep their processing order.
+struct Pending<'a> {
+    log_time: u64,
+    arrival: u64,
+    message: mcap::Message<'a>,
+}
+
+impl PartialEq for Pending<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Pending<'_> {}
+
+impl PartialOrd for Pending<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Pending<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.log_time, self.arrival).cmp(&(other.log_time, other.arrival))
+    }
+}
+
+/// Writes messages to the output MCAP, optionally holding them back until no
+/// message within `window` nanoseconds of `log_time` can still arrive.
+///
+/// Memory is bounded by the number of messages produced within one window.
+pub struct OutputQueue<'a> {
+    window: Option<u64>,
+    pending: BinaryHeap<Reverse<Pending<'a>>>,
+    latest: u64,
+    arrivals: u64,
+}
+
+impl<'a> OutputQueue<'a> {
+    /// With no window every message is written straight through.
+    pub fn new(window: Option<u64>) -> Self {
+        Self {
+            window,
+            pending: BinaryHeap::new(),
+            latest: 0,
+            arrivals: 0,
+        }
+    }
+
+    pub fn write<W: Write + Seek>(&mut self, out: &mut mcap::Writer<'a, W>, message: &mcap::Message<'a>) -> mcap::McapResult<()> {
+        let Some(window) = self.window else {
+            return out.write(message);
+        };
+
+        self.latest = self.latest.max(message.log_time);
+        self.pending.push(Reverse(Pending {
+            log_time: message.log_time,
+            arrival: self.arrivals,
+            message: message.clone(),
+        }));
+        self.arrivals += 1;
+
+        let ready_before = self.latest.saturating_sub(window);
+        while let Some(Reverse(next)) = self.pending.peek() {
+            if next.log_time > ready_before {
+                break;
+            }
+            let Reverse(next) = self.pending.pop().unwrap();
+            out.write(&next.message)?;
+        }
+        Ok(())
+    }
+
+    /// Writes every message still buffered, in `log_time` order.
+    pub fn flush<W: Write + Seek>(&mut self, out: &mut mcap::Writer<'a, W>) -> mcap::McapResult<()> {
+        while let Some(Reverse(next)) = self.pending.pop() {
+            out.write(&next.message)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,67 @@
+//! References back to the source CompressedImage message, embedded in each
+//! encoded frame by `--embed-source-ref` so that frames extracted from the
+//! video outside MCAP can still be traced to where they came from.
+
+use crate::json_string;
+
+/// UUID of the H.264 `user_data_unregistered` SEI messages carrying a reference.
+/// It is the ASCII text `mcap-videoify-sr`.
+const SEI_UUID: [u8; 16] = *b"mcap-videoify-sr";
+
+/// NAL unit types of coded slices, before which the SEI is inserted.
+const SLICE_NAL_TYPES: [u8; 2] = [1, 5];
+
+pub fn to_json(topic: &str, sequence: u32, log_time: u64) -> String {
+    format!(
+        "{{\"topic\":{},\"sequence\":{},\"log_time\":{}}}",
+        json_string(topic),
+        sequence,
+        log_time
+    )
+}
+
+/// Builds an Annex B SEI NAL unit holding `text` as `user_data_unregistered`.
+fn sei_nal(text: &str) -> Vec<u8> {
+    let mut rbsp = vec![5]; // payloadType: user_data_unregistered
+    let mut size = SEI_UUID.len() + text.len();
+    while size >= 255 {
+        rbsp.push(255);
+        size -= 255;
+    }
+    rbsp.push(size as u8);
+    rbsp.extend_from_slice(&SEI_UUID);
+    rbsp.extend_from_slice(text.as_bytes());
+    rbsp.push(0x80); // rbsp_trailing_bits
+
+    let mut nal = vec![0, 0, 0, 1, 6];
+    let mut zeros = 0;
+    for byte in rbsp {
+        // Emulation prevention, so the payload never looks like a start code.
+        if zeros >= 2 && byte <= 3 {
+            nal.push(3);
+            zeros = 0;
+        }
+        nal.push(byte);
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+    }
+    nal
+}
+
+/// Inserts an SEI carrying `text` into an encoded H.264 access unit, after
+/// any parameter sets and before its first slice, as the standard requires.
+pub fn insert_sei(annex_b: &mut Vec<u8>, text: &str) {
+    let first_slice = (0..annex_b.len().saturating_sub(3))
+        .find(|&i| annex_b[i..i + 3] == [0, 0, 1] && SLICE_NAL_TYPES.contains(&(annex_b[i + 3] & 0x1f)))
+        .map_or(0, |i| if i > 0 && annex_b[i - 1] == 0 { i - 1 } else { i });
+    annex_b.splice(first_slice..first_slice, sei_nal(text));
+}
+
+/// Inserts a COM segment carrying `text` right after a JPEG's SOI marker.
+pub fn insert_jpeg_comment(jpeg: &mut Vec<u8>, text: &str) {
+    // The segment length counts its own two bytes and is capped at 16 bits.
+    let text = &text.as_bytes()[..text.len().min(usize::from(u16::MAX) - 2)];
+    let mut segment = vec![0xff, 0xfe];
+    segment.extend_from_slice(&(text.len() as u16 + 2).to_be_bytes());
+    segment.extend_from_slice(text);
+    jpeg.splice(2..2, segment);
+}
@@ -0,0 +1,197 @@
+//! Per-topic `log_time` sanity checks used by `--check-timing`.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Intervals longer than this multiple of a topic's median interval are reported as gaps.
+const LARGE_GAP_FACTOR: u64 = 3;
+
+/// How many of the worst anomalies of each kind are listed per topic.
+const WORST_OFFENDERS: usize = 5;
+
+/// Collects the `log_time` sequence of every topic it is fed.
+#[derive(Default)]
+pub struct TimingLog {
+    topics: BTreeMap<String, Vec<u64>>,
+}
+
+/// A pair of consecutive messages whose timing looks wrong.
+struct Anomaly {
+    index: usize,
+    prev: u64,
+    time: u64,
+}
+
+impl Anomaly {
+    fn magnitude(&self) -> u64 {
+        self.time.abs_diff(self.prev)
+    }
+}
+
+/// Formats nanoseconds as `seconds.nanoseconds`.
+pub fn format_time(ns: u64) -> String {
+    format!("{}.{:09}", ns / 1_000_000_000, ns % 1_000_000_000)
+}
+
+/// Unit suffixes accepted by [`parse_duration`], with their length in nanoseconds.
+const DURATION_UNITS: &[(&str, u64)] = &[
+    ("ns", 1),
+    ("us", 1_000),
+    ("µs", 1_000),
+    ("ms", 1_000_000),
+    ("s", 1_000_000_000),
+    ("m", 60_000_000_000),
+    ("min", 60_000_000_000),
+    ("h", 3_600_000_000_000),
+];
+
+/// Parses a non-negative duration such as `1.5s`, `1500ms` or `250us` into
+/// nanoseconds. A bare number is in seconds. Integers are converted exactly;
+/// fractions are rounded to the nearest nanosecond.
+pub fn parse_duration(value: &str) -> Option<u64> {
+    let split = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let scale = match unit.trim_start() {
+        "" => 1_000_000_000,
+        unit => DURATION_UNITS.iter().find(|(name, _)| *name == unit)?.1,
+    };
+    if let Ok(whole) = number.parse::<u64>() {
+        return whole.checked_mul(scale);
+    }
+    let fraction: f64 = number.parse().ok()?;
+    let ns = (fraction * scale as f64).round();
+    (ns.is_finite() && ns < u64::MAX as f64).then_some(ns as u64)
+}
+
+fn format_ms(ns: u64) -> String {
+    format!("{:.3} ms", ns as f64 / 1_000_000.0)
+}
+
+impl TimingLog {
+    pub fn record(&mut self, topic: &str, log_time: u64) {
+        match self.topics.get_mut(topic) {
+            Some(times) => times.push(log_time),
+            None => {
+                self.topics.insert(topic.to_string(), vec![log_time]);
+            }
+        }
+    }
+
+    /// Renders a human readable report of backwards jumps, large gaps and
+    /// duplicate timestamps for every recorded topic.
+    ///
+    /// Backwards jumps are numbered by storage position, gaps and duplicates
+    /// by position in `log_time` order.
+    pub fn report(&self, title: &str) -> String {
+        let mut out = format!("Timing report ({}):\n", title);
+        if self.topics.is_empty() {
+            out.push_str("  no image topics found\n");
+        }
+
+        for (topic, times) in &self.topics {
+            // Messages are recorded in storage order, which needn't be log_time
+            // order across chunks. Backwards jumps are reported in that order;
+            // intervals, gaps and duplicates are measured on the sorted times so
+            // out-of-order storage doesn't skew the median.
+            let mut sorted = times.clone();
+            sorted.sort_unstable();
+            let mut intervals: Vec<u64> = sorted
+                .windows(2)
+                .filter(|w| w[1] > w[0])
+                .map(|w| w[1] - w[0])
+                .collect();
+            intervals.sort_unstable();
+            let median = intervals.get(intervals.len() / 2).copied();
+
+            let mut backwards = Vec::new();
+            for (index, w) in times.windows(2).enumerate() {
+                if w[1] < w[0] {
+                    backwards.push(Anomaly { index: index + 1, prev: w[0], time: w[1] });
+                }
+            }
+            let mut gaps = Vec::new();
+            let mut duplicates = Vec::new();
+            for (index, w) in sorted.windows(2).enumerate() {
+                let anomaly = Anomaly { index: index + 1, prev: w[0], time: w[1] };
+                if w[1] == w[0] {
+                    duplicates.push(anomaly);
+                } else if median.is_some_and(|m| w[1] - w[0] > m * LARGE_GAP_FACTOR) {
+                    gaps.push(anomaly);
+                }
+            }
+
+            let _ = writeln!(
+                out,
+                "  {}: {} frames, median interval {}",
+                topic,
+                times.len(),
+                median.map(format_ms).unwrap_or_else(|| "n/a".to_string())
+            );
+            write_anomalies(&mut out, "backwards jumps", &mut backwards, "-");
+            write_anomalies(
+                &mut out,
+                &format!("large gaps (> {}x median)", LARGE_GAP_FACTOR),
+                &mut gaps,
+                "+",
+            );
+            write_anomalies(&mut out, "duplicate timestamps", &mut duplicates, "+");
+        }
+
+        out
+    }
+}
+
+fn write_anomalies(out: &mut String, label: &str, anomalies: &mut [Anomaly], sign: &str) {
+    let _ = writeln!(out, "    {}: {}", label, anomalies.len());
+    anomalies.sort_by_key(|a| std::cmp::Reverse(a.magnitude()));
+    for a in anomalies.iter().take(WORST_OFFENDERS) {
+        let _ = writeln!(
+            out,
+            "      frame {}: {} -> {} ({}{})",
+            a.index,
+            format_time(a.prev),
+            format_time(a.time),
+            sign,
+            format_ms(a.magnitude())
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PERIOD_NS: u64 = 33_333_333;
+
+    #[test]
+    fn out_of_order_storage_keeps_the_frame_period() {
+        // Four groups of five frames, each group stored in reverse.
+        let mut log = TimingLog::default();
+        for group in 0..4 {
+            for i in (0..5).rev() {
+                log.record("/cam", (group * 5 + i) * PERIOD_NS);
+            }
+        }
+        let report = log.report("input");
+        assert!(report.contains("/cam: 20 frames, median interval 33.333 ms\n"), "{}", report);
+        assert!(report.contains("backwards jumps: 16\n"), "{}", report);
+        assert!(report.contains("large gaps (> 3x median): 0\n"), "{}", report);
+        assert!(report.contains("duplicate timestamps: 0\n"), "{}", report);
+    }
+
+    #[test]
+    fn gaps_and_duplicates_are_numbered_in_log_time_order() {
+        let mut log = TimingLog::default();
+        for time in [2, 0, 1, 1, 3, 10, 4] {
+            log.record("/cam", time * PERIOD_NS);
+        }
+        let report = log.report("input");
+        assert!(report.contains("median interval 33.333 ms\n"), "{}", report);
+        assert!(report.contains("backwards jumps: 2\n"), "{}", report);
+        // Sorted: 0 1 1 2 3 4 10.
+        assert!(report.contains("frame 2: 0.033333333 -> 0.033333333 (+0.000 ms)\n"), "{}", report);
+        assert!(report.contains("frame 6: 0.133333332 -> 0.333333330 (+200.000 ms)\n"), "{}", report);
+    }
+}
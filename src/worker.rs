@@ -0,0 +1,628 @@
+//! Decoding and encoding of one output video topic, run on a thread of its own.
+//!
+//! Topics share nothing but the output file, so `convert_segments` hands each
+//! image message to the worker of its output video topic and writes the
+//! results back in input order. A worker handles its own messages in the order
+//! it receives them, so the output matches a conversion done one message at a
+//! time, whatever the number of topics.
+
+use crate::profile::{self, Profile};
+use crate::{foxglove, frame, h264, new_encoder, source_ref, yuv, Codec, ConvertOptions, FormatChange, LogBuffer, LogLevel, OnError};
+use anyhow::Result;
+use image::io::Reader as ImageReader;
+use openh264::encoder::FrameType;
+use openh264::formats::YUVBuffer;
+use protobuf::well_known_types::timestamp::Timestamp;
+use protobuf::Message;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::io::Cursor;
+use std::sync::mpsc::{Receiver, Sender};
+use std::time::{Duration, Instant};
+
+/// Work sent to a topic's worker, in input order.
+pub enum Job {
+    Frame(FrameJob),
+    /// The output starts a new segment, which must be decodable on its own.
+    NewSegment,
+    /// The input is done. Answered with one [`FrameOutput`] holding whatever
+    /// the topic still has to write.
+    Flush,
+}
+
+/// One image message, with the fields the main thread parsed out of it.
+pub struct FrameJob {
+    /// Input topic of the message.
+    pub topic: String,
+    pub sequence: u32,
+    pub log_time: u64,
+    /// Timestamp of the image; frames of an animation are offset from it.
+    pub time: Timestamp,
+    pub frame_id: String,
+    pub image: Vec<u8>,
+    /// Pixel layout of a foxglove.RawImage; `None` for a CompressedImage.
+    pub raw: Option<RawLayout>,
+}
+
+pub struct RawLayout {
+    pub encoding: String,
+    pub width: u32,
+    pub height: u32,
+    pub step: u32,
+}
+
+/// What happened to the source message.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Outcome {
+    /// Decoded and handed to the encoder.
+    Transcoded,
+    /// To be copied to the output unchanged, after any `frames`.
+    PassedThrough,
+    /// Dropped, writing nothing.
+    Skipped,
+    /// The answer to [`Job::Flush`]. Its frames belong to the topic's last message.
+    Flushed,
+}
+
+/// One video frame ready to be written.
+pub struct OutputFrame {
+    /// Serialized CompressedVideo, or CompressedImage for `--codec mjpeg`.
+    pub message: Vec<u8>,
+    /// Nanoseconds from the source message's times, for animation frames.
+    pub offset_ns: u64,
+    /// Size of the encoded frame alone.
+    pub bytes: usize,
+    pub keyframe: bool,
+    pub temporal_id: u8,
+    pub source_size: (u32, u32),
+    pub encoded_size: (u32, u32),
+    /// JPEG thumbnail for `--embed-keyframe-thumbs`.
+    pub thumbnail: Option<Vec<u8>>,
+}
+
+/// The result of converting one [`FrameJob`].
+pub struct FrameOutput {
+    pub outcome: Outcome,
+    /// Whether the outcome is `--on-error` handling a failure.
+    pub failed: bool,
+    pub frames: Vec<OutputFrame>,
+    /// Lines to log once the message is written.
+    pub log: LogBuffer,
+    pub decode_time: Duration,
+}
+
+/// Totals a worker hands back when the input is done.
+pub struct TopicSummary {
+    /// Frames openh264's rate control chose not to code.
+    pub skipped_frames: u64,
+    pub profile: Profile,
+}
+
+/// The encoder for one output video topic plus the running state kept alongside it.
+struct TopicEncoder {
+    encoder: h264::H264Encoder,
+    /// Bytes of the frames emitted after the last IDR frame, not counting the IDR itself.
+    gop_bytes: usize,
+    /// Number of frames handed to the encoder so far.
+    frame_count: u64,
+    /// Frames handed to the encoder since the last IDR frame, including it.
+    frames_since_idr: u64,
+}
+
+/// A frame openh264's rate control skipped, kept in case it ends the topic.
+struct SkippedFrame {
+    rgb8: image::RgbImage,
+    /// The frame's message, without its image data.
+    job: FrameJob,
+    offset_ns: u64,
+    source_size: (u32, u32),
+}
+
+/// Converts the images of one output video topic. Several input topics feed
+/// one worker when renames merge them, so source state is kept per input topic.
+pub struct TopicWorker<'a> {
+    opts: &'a ConvertOptions,
+    tone_lut: Option<[u8; 256]>,
+    /// Output video topic.
+    topic: String,
+    encoder: Option<TopicEncoder>,
+    // Map of input topic -> format of its first image (or latest, after a reinit)
+    source_formats: HashMap<String, frame::SourceFormat>,
+    // Input topics already warned about carrying high-bit-depth images
+    hdr_warned: HashSet<String>,
+    // Input topics already warned about carrying RawImage encodings that can't be converted
+    raw_warned: HashSet<String>,
+    // Map of input topic -> size of its latest decoded image, used to scale --error-image
+    source_sizes: HashMap<String, (u32, u32)>,
+    // Map of input topic -> --palette colours, built from its first frame
+    palettes: HashMap<String, frame::Palette>,
+    skipped_frames: u64,
+    /// The last frame of the latest message, when rate control skipped it.
+    skipped_tail: Option<SkippedFrame>,
+    profile: Profile,
+}
+
+impl<'a> TopicWorker<'a> {
+    pub fn new(opts: &'a ConvertOptions, tone_lut: Option<[u8; 256]>, topic: String) -> Self {
+        Self {
+            opts,
+            tone_lut,
+            topic,
+            encoder: None,
+            source_formats: HashMap::new(),
+            hdr_warned: HashSet::new(),
+            raw_warned: HashSet::new(),
+            source_sizes: HashMap::new(),
+            palettes: HashMap::new(),
+            skipped_frames: 0,
+            skipped_tail: None,
+            profile: Profile::new(),
+        }
+    }
+
+    /// Converts every job until the main thread hangs up, sending one result per frame job.
+    pub fn run(mut self, jobs: Receiver<Job>, results: Sender<Result<FrameOutput>>) -> TopicSummary {
+        for job in jobs {
+            match job {
+                Job::Frame(job) => {
+                    // The main thread only stops listening when it is giving up on the run.
+                    let _ = results.send(self.convert(job));
+                }
+                Job::Flush => {
+                    let _ = results.send(self.flush());
+                }
+                Job::NewSegment => {
+                    if let Some(topic_encoder) = &mut self.encoder {
+                        topic_encoder.encoder.force_keyframe();
+                        topic_encoder.gop_bytes = 0;
+                    }
+                }
+            }
+        }
+        TopicSummary {
+            skipped_frames: self.skipped_frames,
+            profile: self.profile,
+        }
+    }
+
+    fn convert(&mut self, job: FrameJob) -> Result<FrameOutput> {
+        let opts = self.opts;
+        let silent = opts.silent;
+        let mut out = FrameOutput {
+            outcome: Outcome::Transcoded,
+            failed: false,
+            frames: Vec::new(),
+            log: LogBuffer::default(),
+            decode_time: Duration::ZERO,
+        };
+        let topic = &job.topic;
+        // Only a skipped frame of the topic's last message needs flushing.
+        self.skipped_tail = None;
+
+        let decode_start = Instant::now();
+        let (decoded, container) = match &job.raw {
+            Some(raw) => {
+                if !frame::is_supported_raw_encoding(&raw.encoding) {
+                    if self.raw_warned.insert(topic.clone()) {
+                        warning!(
+                            out.log,
+                            "{} has RawImage encoding {:?}, which can't be converted; leaving its images as-is",
+                            topic,
+                            raw.encoding
+                        );
+                    }
+                    out.outcome = Outcome::PassedThrough;
+                    return Ok(out);
+                }
+                let decoded = frame::decode_raw(&raw.encoding, raw.width, raw.height, raw.step, &job.image);
+                (decoded.map(|(rgb8, color)| (vec![(rgb8, 0)], color)), None)
+            }
+            None => {
+                let reader = ImageReader::new(Cursor::new(&job.image))
+                    .with_guessed_format()
+                    .expect("Cursor io never fails");
+                // Animated payloads expand to several frames, each offset from the message's times.
+                let container = reader.format();
+                let decoded = match frame::decode_animation(&job.image, container) {
+                    Ok(Some(frames)) => Ok((frames, image::ColorType::Rgba8)),
+                    Ok(None) => reader.decode().map_err(anyhow::Error::from).map(|image| {
+                        let color = image.color();
+                        let rgb8 = match opts.tonemap {
+                            Some(op) if frame::is_high_bit_depth(color) => frame::tonemap(&image, op),
+                            _ => image.to_rgb8(),
+                        };
+                        (vec![(rgb8, 0)], color)
+                    }),
+                    Err(e) => Err(e),
+                };
+                (decoded, container)
+            }
+        };
+        let mut substituted = false;
+        let (frames, color) = match decoded {
+            Ok(decoded) => {
+                self.source_sizes.insert(topic.clone(), decoded.0[0].0.dimensions());
+                decoded
+            }
+            Err(e) => {
+                let placeholder = opts
+                    .error_image
+                    .as_ref()
+                    .zip(self.source_sizes.get(topic))
+                    .map(|(image, &(width, height))| image.render(width, height));
+                match placeholder {
+                    Some(placeholder) => {
+                        if !silent {
+                            status!(out.log, "Substituting --error-image for message {} on {}: {:#}", job.sequence, topic, e);
+                        }
+                        substituted = true;
+                        (vec![(placeholder, 0)], image::ColorType::Rgb8)
+                    }
+                    None if opts.on_error != OnError::Fail => {
+                        let error = anyhow::anyhow!("Couldn't decode image in message {} on {}: {:#}", job.sequence, topic, e);
+                        return fail(opts, opts.on_error, out, error);
+                    }
+                    // A topic's first image has no size to scale --error-image to.
+                    None if opts.error_image.is_some() => anyhow::bail!(
+                        "Couldn't decode image in message {} on {}: {:#}. --error-image needs an earlier image on the topic to take its size from; use --on-error skip or passthrough to continue",
+                        job.sequence,
+                        topic,
+                        e
+                    ),
+                    None => anyhow::bail!(
+                        "Couldn't decode image in message {} on {}: {:#}. Use --on-error skip or passthrough, or --error-image, to continue",
+                        job.sequence,
+                        topic,
+                        e
+                    ),
+                }
+            }
+        };
+        out.decode_time = decode_start.elapsed();
+        self.profile.add(profile::Stage::Decode, out.decode_time);
+        if frames.len() > 1 && !opts.expand_animations {
+            let error = anyhow::anyhow!(
+                "Message {} on topic {} is an animated image with {} frames. Use --expand-animations to encode every frame",
+                job.sequence,
+                topic,
+                frames.len()
+            );
+            return fail(opts, opts.on_error, out, error);
+        }
+
+        if frame::is_high_bit_depth(color) && !substituted {
+            if opts.preserve_hdr {
+                if !silent {
+                    status!(out.log, "Leaving {:?} image on {} as-is (--preserve-hdr)", color, topic);
+                }
+                out.outcome = Outcome::PassedThrough;
+                return Ok(out);
+            }
+            if opts.tonemap.is_none() && self.hdr_warned.insert(topic.clone()) {
+                warning!(
+                    out.log,
+                    "{} has {:?} images; they are truncated to 8 bits. Use --tonemap or --preserve-hdr to handle them",
+                    topic,
+                    color
+                );
+            }
+        }
+
+        let source = frame::SourceFormat {
+            container,
+            color,
+            size: frames[0].0.dimensions(),
+        };
+        match self.source_formats.entry(topic.clone()) {
+            // A placeholder stands in for a frame of the topic's current format.
+            _ if substituted => {}
+            Entry::Vacant(entry) => {
+                entry.insert(source);
+            }
+            Entry::Occupied(mut entry) if *entry.get() != source => match opts.on_format_change {
+                FormatChange::Error => anyhow::bail!(
+                    "Topic {} changed image format from {} to {} at message {}. Use --on-format-change reinit or skip to continue",
+                    topic,
+                    entry.get(),
+                    source,
+                    job.sequence
+                ),
+                FormatChange::Skip => {
+                    if !silent {
+                        status!(out.log, "Skipping {} image on {}: topic started as {}", source, topic, entry.get());
+                    }
+                    out.outcome = Outcome::Skipped;
+                    return Ok(out);
+                }
+                FormatChange::Reinit => {
+                    if !silent {
+                        status!(out.log, "Topic {} changed from {} to {}: restarting the encoder", topic, entry.get(), source);
+                    }
+                    entry.insert(source);
+                    self.encoder = None;
+                    self.palettes.remove(topic);
+                }
+            },
+            Entry::Occupied(_) => {}
+        }
+
+        for (mut rgb8, offset_ns) in frames {
+            let process_start = Instant::now();
+            let source_size = rgb8.dimensions();
+            if opts.size_limit.is_set() {
+                rgb8 = opts.size_limit.apply(rgb8);
+            }
+            if let Some(lut) = &self.tone_lut {
+                frame::apply_lut(&mut rgb8, lut);
+            }
+            if let Some(size) = opts.palette_size {
+                self.palettes
+                    .entry(topic.clone())
+                    .or_insert_with(|| frame::Palette::from_image(&rgb8, size))
+                    .apply(&mut rgb8);
+            }
+            if opts.codec == Codec::H264 && opts.pass_through_small_images && !frame::fits_encoder(&rgb8) {
+                if !silent {
+                    status!(
+                        out.log,
+                        "Leaving {}x{} image on {} as-is: too small for the encoder",
+                        rgb8.width(),
+                        rgb8.height(),
+                        topic
+                    );
+                }
+                out.outcome = Outcome::PassedThrough;
+                break;
+            }
+            self.profile.since(profile::Stage::Process, process_start);
+            match self.encode_frame(&rgb8, &job, offset_ns, source_size, &mut out.log) {
+                Ok(Some(frame)) => out.frames.push(frame),
+                Ok(None) => {}
+                Err(e) => return fail(opts, opts.on_error, out, e),
+            }
+        }
+        Ok(out)
+    }
+
+    /// Encodes again, as a keyframe, a last frame that rate control skipped, so
+    /// every topic ends on its final image.
+    fn flush(&mut self) -> Result<FrameOutput> {
+        let mut out = FrameOutput {
+            outcome: Outcome::Flushed,
+            failed: false,
+            frames: Vec::new(),
+            log: LogBuffer::default(),
+            decode_time: Duration::ZERO,
+        };
+        let Some(tail) = self.skipped_tail.take() else {
+            return Ok(out);
+        };
+        if let Some(topic_encoder) = &mut self.encoder {
+            topic_encoder.encoder.force_keyframe();
+        }
+        if !self.opts.silent {
+            status!(out.log, "Encoding the skipped last frame of {} again as a keyframe", self.topic);
+        }
+        match self.encode_frame(&tail.rgb8, &tail.job, tail.offset_ns, tail.source_size, &mut out.log) {
+            Ok(frame) => out.frames.extend(frame),
+            Err(e) if self.opts.on_error == OnError::Fail => return Err(e),
+            Err(e) => warning!(out.log, "{:#}; the video ends on an earlier frame", e),
+        }
+        Ok(out)
+    }
+
+    /// Encodes one processed frame of `job` and packages it as an output
+    /// message; `None` when nothing is to be written for it.
+    fn encode_frame(
+        &mut self,
+        rgb8: &image::RgbImage,
+        job: &FrameJob,
+        offset_ns: u64,
+        source_size: (u32, u32),
+        log: &mut LogBuffer,
+    ) -> Result<Option<OutputFrame>> {
+        let opts = self.opts;
+        let topic = &job.topic;
+
+        let encoded = match opts.codec {
+            Codec::H264 => self.encode_h264(rgb8, job, log),
+            Codec::Mjpeg => {
+                let encode_start = Instant::now();
+                let encoded = frame::encode_jpeg(rgb8, opts.jpeg_quality)
+                    .map_err(|e| anyhow::anyhow!("Couldn't encode message {} on {}: {:#}", job.sequence, self.topic, e));
+                self.profile.since(profile::Stage::Encode, encode_start);
+                // Every JPEG stands alone, so each frame counts as a keyframe.
+                encoded.map(|frame_data| (FrameType::IDR, 0, frame_data))
+            }
+            #[cfg(feature = "raw-codec")]
+            Codec::Raw => Ok((FrameType::IDR, 0, crate::raw_frame(rgb8))),
+        };
+        let (frame_type, temporal_id, mut frame_data) = encoded?;
+        // H.264 frames too small or odd-sized for openh264 are padded, then cropped back.
+        let encoded_size = match opts.codec {
+            Codec::H264 => frame::cropped_size(rgb8.dimensions()),
+            _ => rgb8.dimensions(),
+        };
+        let frame_len = frame_data.len();
+        if opts.embed_source_ref && frame_len > 0 {
+            let text = source_ref::to_json(topic, job.sequence, job.log_time);
+            match opts.codec {
+                Codec::H264 => source_ref::insert_sei(&mut frame_data, &text),
+                Codec::Mjpeg => source_ref::insert_jpeg_comment(&mut frame_data, &text),
+                #[cfg(feature = "raw-codec")]
+                Codec::Raw => unreachable!("--embed-source-ref is rejected with --codec raw"),
+            }
+        }
+
+        // openh264 codes every frame it is given before returning, so nothing is
+        // left buffered in the encoder. An empty frame is one its rate control
+        // skipped under --allow-frame-skip; it is left out of the output, but the
+        // one ending a topic is encoded again by `flush`.
+        let keyframe = frame_type == FrameType::IDR;
+        if frame_len == 0 {
+            self.skipped_tail = Some(SkippedFrame {
+                rgb8: rgb8.clone(),
+                job: FrameJob {
+                    topic: job.topic.clone(),
+                    sequence: job.sequence,
+                    log_time: job.log_time,
+                    time: job.time.clone(),
+                    frame_id: job.frame_id.clone(),
+                    image: Vec::new(),
+                    raw: None,
+                },
+                offset_ns,
+                source_size,
+            });
+            return Ok(None);
+        }
+        self.skipped_tail = None;
+        if opts.only_keyframes && !keyframe {
+            return Ok(None);
+        }
+
+        let nanos = i64::from(job.time.nanos) + offset_ns as i64;
+        let mut out_time = Timestamp::new();
+        out_time.seconds = job.time.seconds + nanos.div_euclid(1_000_000_000);
+        out_time.nanos = nanos.rem_euclid(1_000_000_000) as i32;
+
+        let message = match opts.codec {
+            Codec::H264 => {
+                let mut out_msg = foxglove::CompressedVideo::CompressedVideo::new();
+                out_msg.timestamp = protobuf::MessageField::some(out_time);
+                out_msg.frame_id = job.frame_id.clone();
+                out_msg.format = "h264".to_string();
+                out_msg.data = frame_data;
+                out_msg.write_to_bytes().unwrap()
+            }
+            #[cfg(feature = "raw-codec")]
+            Codec::Raw => {
+                let mut out_msg = foxglove::CompressedVideo::CompressedVideo::new();
+                out_msg.timestamp = protobuf::MessageField::some(out_time);
+                out_msg.frame_id = job.frame_id.clone();
+                out_msg.format = "rgb8".to_string();
+                out_msg.data = frame_data;
+                out_msg.write_to_bytes().unwrap()
+            }
+            Codec::Mjpeg => {
+                let mut out_msg = foxglove::CompressedImage::CompressedImage::new();
+                out_msg.timestamp = protobuf::MessageField::some(out_time);
+                out_msg.frame_id = job.frame_id.clone();
+                out_msg.format = "jpeg".to_string();
+                out_msg.data = frame_data;
+                out_msg.write_to_bytes().unwrap()
+            }
+        };
+        let thumbnail = match opts.embed_keyframe_thumbs && keyframe {
+            true => match frame::encode_jpeg(&frame::thumbnail(rgb8), 75) {
+                Ok(thumbnail) => Some(thumbnail),
+                Err(e) => {
+                    anyhow::bail!("Couldn't encode the keyframe thumbnail of message {} on {}: {:#}", job.sequence, topic, e)
+                }
+            },
+            false => None,
+        };
+        Ok(Some(OutputFrame {
+            message,
+            offset_ns,
+            bytes: frame_len,
+            keyframe,
+            temporal_id,
+            source_size,
+            encoded_size,
+            thumbnail,
+        }))
+    }
+
+    /// Encodes one frame with the topic's encoder, creating it on first use. A
+    /// frame openh264 can't take is padded, and the SPS crops the padding off.
+    fn encode_h264(&mut self, rgb8: &image::RgbImage, job: &FrameJob, log: &mut LogBuffer) -> Result<(FrameType, u8, Vec<u8>)> {
+        let opts = self.opts;
+        let padded;
+        let (picture, rgb8) = match frame::fits_encoder(rgb8) {
+            true => (None, rgb8),
+            false => {
+                padded = frame::pad_for_encoder(rgb8);
+                (Some(frame::cropped_size(rgb8.dimensions())), &padded)
+            }
+        };
+        if self.encoder.is_none() {
+            if !opts.silent {
+                status!(log, "Encoding {} at {} bps", self.topic, opts.encoder.bitrate_bps(rgb8.width(), rgb8.height()));
+            }
+            let mut encoder = new_encoder(rgb8.width(), rgb8.height(), &opts.encoder, opts.keyframe_interval)?;
+            if let Some((width, height)) = picture {
+                encoder.crop_to(width, height);
+            }
+            self.encoder = Some(TopicEncoder {
+                encoder,
+                gop_bytes: 0,
+                frame_count: 0,
+                frames_since_idr: 0,
+            });
+        }
+        let topic_encoder = self.encoder.as_mut().unwrap();
+
+        // Every topic, and every restart of its encoder, begins decodable. The
+        // interval runs from the last IDR, whatever forced it.
+        if topic_encoder.frame_count == 0
+            || opts.keyframe_interval > 0 && topic_encoder.frames_since_idr >= opts.keyframe_interval
+        {
+            topic_encoder.encoder.force_keyframe();
+        }
+        let frame_index = topic_encoder.frame_count;
+        topic_encoder.frame_count += 1;
+
+        let width = usize::try_from(rgb8.width()).unwrap();
+        let height = usize::try_from(rgb8.height()).unwrap();
+        let yuv_start = Instant::now();
+        let yuv = YUVBuffer::with_rgb(width, height, rgb8);
+        let aligned = opts.yuv_alignment.map(|alignment| yuv::AlignedYuv::new(&yuv, alignment));
+        self.profile.since(profile::Stage::Yuv, yuv_start);
+
+        let encode_start = Instant::now();
+        let encoded = match &aligned {
+            Some(aligned) => topic_encoder.encoder.encode(aligned),
+            None => topic_encoder.encoder.encode(&yuv),
+        };
+        let encoded = match encoded {
+            Ok(encoded) => encoded,
+            Err(e) => {
+                // Start over at a keyframe, in case the failure left the encoder in a bad state.
+                self.encoder = None;
+                anyhow::bail!("Couldn't encode message {} on {}: {:#}", job.sequence, self.topic, e);
+            }
+        };
+        let h264::EncodedFrame { data: frame_data, frame_type, temporal_id } = encoded;
+        self.profile.since(profile::Stage::Encode, encode_start);
+
+        if frame_type == FrameType::IDR {
+            topic_encoder.gop_bytes = 0;
+            topic_encoder.frames_since_idr = 0;
+            if !opts.silent {
+                status!(log, "Keyframe at frame {} on {}", frame_index, self.topic);
+            }
+        } else {
+            topic_encoder.gop_bytes += frame_data.len();
+        }
+        topic_encoder.frames_since_idr += 1;
+        if frame_data.is_empty() {
+            self.skipped_frames += 1;
+        }
+        if opts.max_gop_bytes.is_some_and(|max| topic_encoder.gop_bytes >= max) {
+            topic_encoder.encoder.force_keyframe();
+            topic_encoder.gop_bytes = 0;
+        }
+        Ok((frame_type, temporal_id, frame_data))
+    }
+}
+
+/// Handles `error`, a failure converting the message of `out`, according to `on_error`.
+fn fail(opts: &ConvertOptions, on_error: OnError, mut out: FrameOutput, error: anyhow::Error) -> Result<FrameOutput> {
+    let pass_through = on_error.recover(opts.silent, &mut out.log, error)?;
+    out.outcome = if pass_through { Outcome::PassedThrough } else { Outcome::Skipped };
+    out.failed = true;
+    // No part of a failed message is written, even frames of an animation that did encode.
+    out.frames.clear();
+    Ok(out)
+}
@@ -0,0 +1,80 @@
+//! I420 frames with padded plane strides, for `--yuv-alignment`.
+
+use openh264::formats::{YUVBuffer, YUVSource};
+
+/// An I420 frame whose rows start on `alignment`-byte boundaries.
+///
+/// [`YUVBuffer`] packs rows tightly (stride = width), which is what openh264
+/// expects; backends that need aligned rows get a padded copy instead. The
+/// padding bytes are outside the picture and never encoded.
+pub struct AlignedYuv {
+    width: i32,
+    height: i32,
+    y: Vec<u8>,
+    u: Vec<u8>,
+    v: Vec<u8>,
+    y_stride: usize,
+    uv_stride: usize,
+}
+
+/// Copies `rows` rows of `row_len` bytes from a tightly packed plane into one with `stride`.
+fn restride(plane: &[u8], row_len: usize, rows: usize, stride: usize) -> Vec<u8> {
+    let mut out = vec![0u8; stride * rows];
+    for (dst, src) in out.chunks_exact_mut(stride).zip(plane.chunks_exact(row_len)) {
+        dst[..row_len].copy_from_slice(src);
+    }
+    out
+}
+
+impl AlignedYuv {
+    /// `alignment` must be a power of two.
+    pub fn new(yuv: &YUVBuffer, alignment: usize) -> Self {
+        let width = yuv.width() as usize;
+        let height = yuv.height() as usize;
+        let y_stride = width.next_multiple_of(alignment);
+        let uv_stride = (width / 2).next_multiple_of(alignment);
+        Self {
+            width: yuv.width(),
+            height: yuv.height(),
+            y: restride(yuv.y(), width, height, y_stride),
+            u: restride(yuv.u(), width / 2, height / 2, uv_stride),
+            v: restride(yuv.v(), width / 2, height / 2, uv_stride),
+            y_stride,
+            uv_stride,
+        }
+    }
+}
+
+impl YUVSource for AlignedYuv {
+    fn width(&self) -> i32 {
+        self.width
+    }
+
+    fn height(&self) -> i32 {
+        self.height
+    }
+
+    fn y(&self) -> &[u8] {
+        &self.y
+    }
+
+    fn u(&self) -> &[u8] {
+        &self.u
+    }
+
+    fn v(&self) -> &[u8] {
+        &self.v
+    }
+
+    fn y_stride(&self) -> i32 {
+        self.y_stride as i32
+    }
+
+    fn u_stride(&self) -> i32 {
+        self.uv_stride as i32
+    }
+
+    fn v_stride(&self) -> i32 {
+        self.uv_stride as i32
+    }
+}
@@ -0,0 +1,405 @@
+//! Builds small MCAP inputs in memory and reads converted outputs back.
+#![allow(dead_code)]
+
+use image::{DynamicImage, ImageOutputFormat, RgbImage};
+use mcap_videoify::{convert, ConvertOptions, ConvertStats};
+use protobuf::descriptor::field_descriptor_proto::{Label, Type};
+use protobuf::descriptor::{DescriptorProto, FieldDescriptorProto, FileDescriptorProto, FileDescriptorSet};
+use protobuf::Message;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::io::Cursor;
+use std::sync::{Arc, Mutex};
+
+/// log_time of a fixture's first message; each following one is a frame later.
+pub const START_NS: u64 = 1_000_000_000;
+pub const FRAME_NS: u64 = 33_333_333;
+/// How much later than its log_time a fixture message was published.
+pub const PUBLISH_DELAY_NS: u64 = 500;
+
+/// A protobuf field of a test schema. Message fields are Timestamps.
+pub struct Field(pub &'static str, pub i32, pub Type);
+
+/// The fields of foxglove.CompressedImage.
+pub const COMPRESSED_IMAGE: &[Field] = &[
+    Field("timestamp", 1, Type::TYPE_MESSAGE),
+    Field("frame_id", 4, Type::TYPE_STRING),
+    Field("data", 2, Type::TYPE_BYTES),
+    Field("format", 3, Type::TYPE_STRING),
+];
+
+/// The fields of foxglove.RawImage.
+pub const RAW_IMAGE: &[Field] = &[
+    Field("timestamp", 1, Type::TYPE_MESSAGE),
+    Field("frame_id", 7, Type::TYPE_STRING),
+    Field("width", 2, Type::TYPE_FIXED32),
+    Field("height", 3, Type::TYPE_FIXED32),
+    Field("encoding", 4, Type::TYPE_STRING),
+    Field("step", 5, Type::TYPE_FIXED32),
+    Field("data", 6, Type::TYPE_BYTES),
+];
+
+/// A FileDescriptorSet defining `foxglove.<message>` with `fields`, preceded
+/// by google/protobuf/timestamp.proto unless `timestamp_first` is false.
+pub fn descriptor_set(message: &str, fields: &[Field], timestamp_first: bool) -> Vec<u8> {
+    let mut proto = DescriptorProto::new();
+    proto.set_name(message.to_string());
+    for Field(name, number, field_type) in fields {
+        let mut field = FieldDescriptorProto::new();
+        field.set_name(name.to_string());
+        field.set_number(*number);
+        field.set_type(*field_type);
+        field.set_label(Label::LABEL_OPTIONAL);
+        if *field_type == Type::TYPE_MESSAGE {
+            field.set_type_name(".google.protobuf.Timestamp".to_string());
+        }
+        proto.field.push(field);
+    }
+    let mut file = FileDescriptorProto::new();
+    file.set_name(format!("foxglove/{}.proto", message));
+    file.set_package("foxglove".to_string());
+    file.set_syntax("proto3".to_string());
+    file.dependency.push("google/protobuf/timestamp.proto".to_string());
+    file.message_type.push(proto);
+
+    let timestamp = protobuf::well_known_types::timestamp::file_descriptor().proto().clone();
+    let mut set = FileDescriptorSet::new();
+    if timestamp_first {
+        set.file.push(timestamp);
+        set.file.push(file);
+    } else {
+        set.file.push(file);
+        set.file.push(timestamp);
+    }
+    set.write_to_bytes().unwrap()
+}
+
+pub fn schema(message: &str, data: Vec<u8>) -> Arc<mcap::Schema<'static>> {
+    Arc::new(mcap::Schema {
+        name: format!("foxglove.{}", message),
+        encoding: "protobuf".to_string(),
+        data: Cow::from(data),
+    })
+}
+
+pub fn compressed_image_schema() -> Arc<mcap::Schema<'static>> {
+    schema("CompressedImage", descriptor_set("CompressedImage", COMPRESSED_IMAGE, true))
+}
+
+pub fn raw_image_schema() -> Arc<mcap::Schema<'static>> {
+    schema("RawImage", descriptor_set("RawImage", RAW_IMAGE, true))
+}
+
+pub fn channel(topic: &str, schema: Option<Arc<mcap::Schema<'static>>>, encoding: &str) -> Arc<mcap::Channel<'static>> {
+    Arc::new(mcap::Channel {
+        topic: topic.to_string(),
+        schema,
+        message_encoding: encoding.to_string(),
+        metadata: BTreeMap::new(),
+    })
+}
+
+/// A protobuf field value, for [`encode`].
+pub enum Value<'a> {
+    Bytes(&'a [u8]),
+    Fixed32(u32),
+    Timestamp(u64),
+}
+
+fn varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Serializes a message from `(field number, value)` pairs.
+pub fn encode(fields: &[(i32, Value)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (number, value) in fields {
+        let number = *number as u64;
+        match value {
+            Value::Bytes(bytes) => {
+                varint(&mut out, number << 3 | 2);
+                varint(&mut out, bytes.len() as u64);
+                out.extend_from_slice(bytes);
+            }
+            Value::Fixed32(value) => {
+                varint(&mut out, number << 3 | 5);
+                out.extend_from_slice(&value.to_le_bytes());
+            }
+            Value::Timestamp(ns) => {
+                let mut timestamp = Vec::new();
+                varint(&mut timestamp, 1 << 3);
+                varint(&mut timestamp, ns / 1_000_000_000);
+                varint(&mut timestamp, 2 << 3);
+                varint(&mut timestamp, ns % 1_000_000_000);
+                varint(&mut out, number << 3 | 2);
+                varint(&mut out, timestamp.len() as u64);
+                out.extend_from_slice(&timestamp);
+            }
+        }
+    }
+    out
+}
+
+/// A CompressedImage message holding `data`, stamped `stamp_ns`.
+pub fn compressed_image(stamp_ns: u64, frame_id: &str, format: &str, data: &[u8]) -> Vec<u8> {
+    encode(&[
+        (1, Value::Timestamp(stamp_ns)),
+        (4, Value::Bytes(frame_id.as_bytes())),
+        (2, Value::Bytes(data)),
+        (3, Value::Bytes(format.as_bytes())),
+    ])
+}
+
+/// A RawImage message holding `img` as tightly packed `rgb8`.
+pub fn raw_image(stamp_ns: u64, img: &RgbImage) -> Vec<u8> {
+    encode(&[
+        (1, Value::Timestamp(stamp_ns)),
+        (7, Value::Bytes(b"raw")),
+        (2, Value::Fixed32(img.width())),
+        (3, Value::Fixed32(img.height())),
+        (4, Value::Bytes(b"rgb8")),
+        (5, Value::Fixed32(img.width() * 3)),
+        (6, Value::Bytes(img.as_raw())),
+    ])
+}
+
+/// A gradient that changes with `index`, so consecutive frames differ.
+pub fn test_image(width: u32, height: u32, index: u32) -> RgbImage {
+    RgbImage::from_fn(width, height, |x, y| {
+        image::Rgb([(x * 4 + index * 3) as u8, (y * 5) as u8, (x + y + index) as u8])
+    })
+}
+
+pub fn encode_image(img: DynamicImage, format: ImageOutputFormat) -> Vec<u8> {
+    let mut data = Vec::new();
+    img.write_to(&mut Cursor::new(&mut data), format).unwrap();
+    data
+}
+
+pub fn jpeg(img: &RgbImage) -> Vec<u8> {
+    encode_image(DynamicImage::ImageRgb8(img.clone()), ImageOutputFormat::Jpeg(90))
+}
+
+pub fn png(img: DynamicImage) -> Vec<u8> {
+    encode_image(img, ImageOutputFormat::Png)
+}
+
+/// Writes `messages`, each a channel and payload, into an MCAP. Message `i`
+/// gets sequence `i`, log_time `START_NS + i * FRAME_NS` and a publish_time
+/// `PUBLISH_DELAY_NS` after it.
+pub fn write_mcap(messages: &[(Arc<mcap::Channel<'static>>, Vec<u8>)]) -> Vec<u8> {
+    let mut out = Cursor::new(Vec::new());
+    let mut writer = mcap::Writer::new(&mut out).unwrap();
+    for (i, (channel, data)) in messages.iter().enumerate() {
+        let time = START_NS + i as u64 * FRAME_NS;
+        writer
+            .write(&mcap::Message {
+                channel: channel.clone(),
+                sequence: i as u32,
+                log_time: time,
+                publish_time: time + PUBLISH_DELAY_NS,
+                data: Cow::from(data.clone()),
+            })
+            .unwrap();
+    }
+    writer.finish().unwrap();
+    drop(writer);
+    out.into_inner()
+}
+
+/// An MCAP with one CompressedImage message per JPEG of `images` on `topic`,
+/// stamped with its log_time.
+pub fn jpeg_mcap(topic: &str, images: &[RgbImage]) -> Vec<u8> {
+    let channel = channel(topic, Some(compressed_image_schema()), "protobuf");
+    let messages: Vec<_> = images
+        .iter()
+        .enumerate()
+        .map(|(i, img)| (channel.clone(), compressed_image(START_NS + i as u64 * FRAME_NS, "cam", "jpeg", &jpeg(img))))
+        .collect();
+    write_mcap(&messages)
+}
+
+/// An MCAP with `frames` JPEG images of `width` x `height` on each of the
+/// topics `/cam0/image`, `/cam1/image`, ..., interleaved one frame per topic in
+/// turn, with a `/tf` message after every round.
+pub fn cameras_mcap(topics: usize, frames: usize, width: u32, height: u32) -> Vec<u8> {
+    let schema = compressed_image_schema();
+    let channels: Vec<_> = (0..topics)
+        .map(|t| channel(&format!("/cam{}/image", t), Some(schema.clone()), "protobuf"))
+        .collect();
+    let tf = channel("/tf", None, "json");
+    let mut messages = Vec::new();
+    for i in 0..frames {
+        for (t, channel) in channels.iter().enumerate() {
+            let data = jpeg(&test_image(width, height, (i * 7 + t * 40) as u32));
+            let stamp = START_NS + messages.len() as u64 * FRAME_NS;
+            messages.push((channel.clone(), compressed_image(stamp, "cam", "jpeg", &data)));
+        }
+        messages.push((tf.clone(), format!("{{\"round\":{}}}", i).into_bytes()));
+    }
+    write_mcap(&messages)
+}
+
+/// A path in the temporary directory, unique to this test process.
+pub fn temp_path(name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("mcap-videoify-{}-{}", std::process::id(), name))
+}
+
+/// Options that keep the log lines, for tests that check them.
+pub fn logged_options() -> (ConvertOptions, Arc<Mutex<Vec<String>>>) {
+    let lines = Arc::new(Mutex::new(Vec::new()));
+    let sink = lines.clone();
+    let opts = ConvertOptions {
+        log: Some(Box::new(move |_, line| sink.lock().unwrap().push(line.to_string()))),
+        ..ConvertOptions::default()
+    };
+    (opts, lines)
+}
+
+pub fn run(input: &[u8], opts: &ConvertOptions) -> (Vec<u8>, ConvertStats) {
+    let mut output = Cursor::new(Vec::new());
+    let stats = convert(input, &mut output, opts).unwrap();
+    (output.into_inner(), stats)
+}
+
+/// One message read back from an output.
+pub struct Output {
+    pub topic: String,
+    pub schema: Option<String>,
+    pub sequence: u32,
+    pub log_time: u64,
+    pub publish_time: u64,
+    pub data: Vec<u8>,
+}
+
+pub fn read_mcap(mcap: &[u8]) -> Vec<Output> {
+    mcap::MessageStream::new(mcap)
+        .unwrap()
+        .map(|message| {
+            let message = message.unwrap();
+            Output {
+                topic: message.channel.topic.clone(),
+                schema: message.channel.schema.as_ref().map(|s| s.name.clone()),
+                sequence: message.sequence,
+                log_time: message.log_time,
+                publish_time: message.publish_time,
+                data: message.data.into_owned(),
+            }
+        })
+        .collect()
+}
+
+pub fn on_topic<'a>(messages: &'a [Output], topic: &'a str) -> impl Iterator<Item = &'a Output> {
+    messages.iter().filter(move |m| m.topic == topic)
+}
+
+/// The raw bytes of length-delimited field `number` of a serialized message.
+pub fn field(data: &[u8], number: u64) -> Option<&[u8]> {
+    let mut i = 0;
+    let read_varint = |i: &mut usize| {
+        let mut value = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = data[*i];
+            *i += 1;
+            value |= u64::from(byte & 0x7f) << shift;
+            shift += 7;
+            if byte < 0x80 {
+                return value;
+            }
+        }
+    };
+    while i < data.len() {
+        let key = read_varint(&mut i);
+        match key & 7 {
+            0 => {
+                read_varint(&mut i);
+            }
+            1 => i += 8,
+            2 => {
+                let len = read_varint(&mut i) as usize;
+                if key >> 3 == number {
+                    return Some(&data[i..i + len]);
+                }
+                i += len;
+            }
+            5 => i += 4,
+            wire_type => panic!("unexpected wire type {}", wire_type),
+        }
+    }
+    None
+}
+
+/// The timestamp of a CompressedImage or CompressedVideo, in nanoseconds.
+pub fn stamp_ns(data: &[u8]) -> u64 {
+    let timestamp = protobuf::well_known_types::timestamp::Timestamp::parse_from_bytes(field(data, 1).unwrap_or(&[])).unwrap();
+    timestamp.seconds as u64 * 1_000_000_000 + timestamp.nanos as u64
+}
+
+/// The image data of a CompressedVideo (field 3).
+pub fn video_data(data: &[u8]) -> &[u8] {
+    field(data, 3).unwrap()
+}
+
+/// The format of a CompressedVideo (field 4).
+pub fn video_format(data: &[u8]) -> &str {
+    std::str::from_utf8(field(data, 4).unwrap_or(&[])).unwrap()
+}
+
+/// Whether an H.264 CompressedVideo holds an IDR slice (NAL type 5).
+pub fn is_keyframe(data: &[u8]) -> bool {
+    video_data(data).windows(4).any(|w| w[..3] == [0, 0, 1] && w[3] & 0x1f == 5)
+}
+
+/// The frame of a `--codec raw` CompressedVideo.
+pub fn raw_frame(data: &[u8]) -> RgbImage {
+    let data = video_data(data);
+    let width = u32::from_le_bytes(data[..4].try_into().unwrap());
+    let height = u32::from_le_bytes(data[4..8].try_into().unwrap());
+    RgbImage::from_raw(width, height, data[8..].to_vec()).unwrap()
+}
+
+/// Options for `--codec raw`, which writes every processed frame losslessly.
+#[cfg(feature = "raw-codec")]
+pub fn raw_codec() -> ConvertOptions {
+    ConvertOptions {
+        codec: mcap_videoify::Codec::Raw,
+        ..ConvertOptions::default()
+    }
+}
+
+/// An MCAP with one lossless PNG CompressedImage message per image on `topic`.
+pub fn png_mcap(topic: &str, images: &[DynamicImage]) -> Vec<u8> {
+    let channel = channel(topic, Some(compressed_image_schema()), "protobuf");
+    let messages: Vec<_> = images
+        .iter()
+        .enumerate()
+        .map(|(i, img)| (channel.clone(), compressed_image(START_NS + i as u64 * FRAME_NS, "cam", "png", &png(img.clone()))))
+        .collect();
+    write_mcap(&messages)
+}
+
+/// Decodes every frame of an H.264 topic.
+pub fn decode_h264<'a>(messages: impl Iterator<Item = &'a Output>) -> Vec<RgbImage> {
+    let mut decoder = openh264::decoder::Decoder::new().unwrap();
+    messages
+        .filter_map(|m| {
+            let yuv = decoder.decode(video_data(&m.data)).unwrap()?;
+            let (width, height) = yuv.dimension_rgb();
+            let mut rgb = vec![0; width * height * 3];
+            yuv.write_rgb8(&mut rgb);
+            RgbImage::from_raw(width as u32, height as u32, rgb)
+        })
+        .collect()
+}
+
+/// Mean absolute difference per channel between two same-sized images.
+pub fn mean_abs_diff(a: &RgbImage, b: &RgbImage) -> f64 {
+    assert_eq!(a.dimensions(), b.dimensions());
+    let total: u64 = a.as_raw().iter().zip(b.as_raw()).map(|(x, y)| u64::from(x.abs_diff(*y))).sum();
+    total as f64 / a.as_raw().len() as f64
+}
@@ -0,0 +1,53 @@
+//! Matched image topics that produce no frames, behind `--drop-empty-topics`.
+
+mod common;
+
+use common::*;
+
+/// Four good frames on /cam/good and four undecodable ones on /cam/bad.
+fn half_broken_mcap() -> Vec<u8> {
+    let good = channel("/cam/good", Some(compressed_image_schema()), "protobuf");
+    let bad = channel("/cam/bad", Some(compressed_image_schema()), "protobuf");
+    let mut messages = Vec::new();
+    for i in 0..4 {
+        let stamp = START_NS + 2 * i as u64 * FRAME_NS;
+        messages.push((good.clone(), compressed_image(stamp, "cam", "jpeg", &jpeg(&test_image(32, 32, i)))));
+        messages.push((bad.clone(), compressed_image(stamp, "cam", "jpeg", b"not a jpeg")));
+    }
+    write_mcap(&messages)
+}
+
+/// The output's channels with their message counts, by topic.
+fn channels(output: &[u8]) -> Vec<(String, u64)> {
+    let summary = mcap::Summary::read(output).unwrap().unwrap();
+    let counts = summary.stats.unwrap().channel_message_counts;
+    let mut channels: Vec<_> = summary
+        .channels
+        .iter()
+        .map(|(id, channel)| (channel.topic.clone(), counts.get(id).copied().unwrap_or(0)))
+        .collect();
+    channels.sort();
+    channels
+}
+
+#[test]
+fn empty_topics_are_left_out_and_named() {
+    let (opts, lines) = logged_options();
+    let (output, stats) = run(&half_broken_mcap(), &opts);
+    assert_eq!((stats.video_frames, stats.failed_messages), (4, 4));
+    assert_eq!(channels(&output), [("/cam/good_video".to_string(), 4)]);
+    let lines = lines.lock().unwrap();
+    assert!(lines.iter().any(|l| l == "No frames were written for /cam/bad_video; left it out of the output"), "{:?}", lines);
+}
+
+#[test]
+fn empty_topics_can_keep_their_channel() {
+    let (mut opts, lines) = logged_options();
+    opts.drop_empty_topics = false;
+    let (output, _) = run(&half_broken_mcap(), &opts);
+    assert_eq!(
+        channels(&output),
+        [("/cam/bad_video".to_string(), 0), ("/cam/good_video".to_string(), 4)]
+    );
+    assert!(!lines.lock().unwrap().iter().any(|l| l.starts_with("No frames were written")));
+}
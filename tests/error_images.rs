@@ -0,0 +1,61 @@
+//! `--error-image` placeholders for images that fail to decode, and how they
+//! combine with `--on-error`.
+
+mod common;
+
+use common::*;
+use mcap_videoify::{ConvertOptions, ErrorImage, OnError};
+
+/// Four JPEG frames on one topic, with undecodable data in message `broken`.
+fn broken_mcap(broken: usize) -> Vec<u8> {
+    let channel = channel("/cam/image", Some(compressed_image_schema()), "protobuf");
+    let messages: Vec<_> = (0..4)
+        .map(|i| {
+            let data = match i == broken {
+                true => b"not a jpeg".to_vec(),
+                false => jpeg(&test_image(32, 24, i as u32)),
+            };
+            (channel.clone(), compressed_image(START_NS + i as u64 * FRAME_NS, "cam", "jpeg", &data))
+        })
+        .collect();
+    write_mcap(&messages)
+}
+
+fn with_error_image(on_error: OnError) -> ConvertOptions {
+    ConvertOptions {
+        on_error,
+        error_image: Some(ErrorImage::parse("#ff00ff").unwrap()),
+        ..ConvertOptions::default()
+    }
+}
+
+#[test]
+fn later_failures_get_the_placeholder_even_under_fail() {
+    let (output, stats) = run(&broken_mcap(2), &with_error_image(OnError::Fail));
+    assert_eq!((stats.video_frames, stats.failed_messages), (4, 0));
+    let messages = read_mcap(&output);
+    let decoded = decode_h264(on_topic(&messages, "/cam/image_video"));
+    let magenta = image::RgbImage::from_pixel(32, 24, image::Rgb([255, 0, 255]));
+    assert!(mean_abs_diff(&decoded[2], &magenta) < 24.0);
+}
+
+#[test]
+fn first_frame_failures_still_fail_under_fail() {
+    let mut output = std::io::Cursor::new(Vec::new());
+    let error = mcap_videoify::convert(&broken_mcap(0), &mut output, &with_error_image(OnError::Fail)).unwrap_err();
+    let error = format!("{:#}", error);
+    assert!(error.contains("Couldn't decode image in message 0 on /cam/image"), "{}", error);
+    assert!(error.contains("--error-image needs an earlier image"), "{}", error);
+}
+
+#[test]
+fn first_frame_failures_follow_on_error() {
+    let (output, stats) = run(&broken_mcap(0), &with_error_image(OnError::Skip));
+    assert_eq!((stats.video_frames, stats.failed_messages), (3, 1));
+    assert_eq!(on_topic(&read_mcap(&output), "/cam/image").count(), 0);
+
+    let (output, stats) = run(&broken_mcap(0), &with_error_image(OnError::PassThrough));
+    assert_eq!((stats.video_frames, stats.failed_messages), (3, 1));
+    let kept: Vec<_> = on_topic(&read_mcap(&output), "/cam/image").map(|m| m.sequence).collect();
+    assert_eq!(kept, [0]);
+}
@@ -0,0 +1,42 @@
+//! Every source image becomes one video message, however tight the bitrate.
+
+mod common;
+
+use common::*;
+
+#[test]
+fn every_image_is_encoded_at_a_low_bitrate() {
+    let images: Vec<_> = (0..60).map(|i| test_image(64, 48, i)).collect();
+    let input = jpeg_mcap("/cam/image", &images);
+    let mut opts = mcap_videoify::ConvertOptions::default();
+    opts.encoder.bitrate = Some(1000);
+    let (output, stats) = run(&input, &opts);
+
+    let messages = read_mcap(&output);
+    let video: Vec<_> = on_topic(&messages, "/cam/image_video").collect();
+    assert_eq!(video.len(), images.len());
+    assert_eq!(stats.video_frames, images.len() as u64);
+    for (i, message) in video.iter().enumerate() {
+        assert_eq!(message.sequence, i as u32);
+        assert_eq!(message.log_time, START_NS + i as u64 * FRAME_NS);
+    }
+}
+
+#[test]
+fn a_skipped_last_frame_is_flushed() {
+    let images: Vec<_> = (0..30).map(|i| test_image(64, 48, i)).collect();
+    let input = jpeg_mcap("/cam/image", &images);
+    let mut opts = mcap_videoify::ConvertOptions::default();
+    opts.encoder.bitrate = Some(1000);
+    opts.encoder.frame_skip = true;
+    let (output, _) = run(&input, &opts);
+
+    // Rate control drops most frames at this bitrate, but not the last one.
+    let messages = read_mcap(&output);
+    let video: Vec<_> = on_topic(&messages, "/cam/image_video").collect();
+    assert!(video.len() < images.len());
+    let last = video.last().unwrap();
+    assert_eq!(last.sequence, images.len() as u32 - 1);
+    assert_eq!(stamp_ns(&last.data), START_NS + (images.len() as u64 - 1) * FRAME_NS);
+    assert_eq!(decode_h264(video.iter().copied()).len(), video.len());
+}
@@ -0,0 +1,85 @@
+//! 16-bit sources, behind `--tonemap` and `--preserve-hdr`.
+
+mod common;
+
+use common::*;
+use image::{DynamicImage, ImageBuffer, Rgb};
+use mcap_videoify::{ConvertOptions, Tonemap};
+
+/// SDR reference white (203 nits) on the 1000-nit scale the tone mapping assumes.
+const SDR_WHITE: u16 = (65535.0 * 203.0 / 1000.0) as u16;
+
+/// Four flat 16-bit frames at SDR reference white.
+fn rgb16_mcap() -> Vec<u8> {
+    let frame = DynamicImage::ImageRgb16(ImageBuffer::from_pixel(32, 32, Rgb([SDR_WHITE; 3])));
+    png_mcap("/cam/image", &vec![frame; 4])
+}
+
+fn decoded(output: &[u8]) -> Vec<image::RgbImage> {
+    let messages = read_mcap(output);
+    decode_h264(on_topic(&messages, "/cam/image_video"))
+}
+
+/// Checks the frames of `output` against flat 8-bit frames of `level` sent
+/// through the same encoder, which shifts flat grays by a few levels.
+fn assert_level(output: &[u8], level: u8) {
+    let reference = DynamicImage::ImageRgb8(ImageBuffer::from_pixel(32, 32, Rgb([level; 3])));
+    let reference = decoded(&run(&png_mcap("/cam/image", &[reference]), &ConvertOptions::default()).0);
+    let frames = decoded(output);
+    assert_eq!(frames.len(), 4);
+    for frame in &frames {
+        let diff = mean_abs_diff(frame, &reference[0]);
+        assert!(diff < 2.0, "{} levels off {}", diff, level);
+    }
+}
+
+#[test]
+fn sixteen_bit_frames_are_truncated_with_one_warning() {
+    let (opts, lines) = logged_options();
+    let (output, stats) = run(&rgb16_mcap(), &opts);
+    assert_eq!(stats.video_frames, 4);
+    // 203/1000 of full scale, kept as 8 bits.
+    assert_level(&output, 52);
+    let warnings = lines.lock().unwrap().iter().filter(|l| l.contains("truncated to 8 bits")).count();
+    assert_eq!(warnings, 1);
+}
+
+#[test]
+fn tonemapping_maps_reference_white_up() {
+    // Reference white sits at 1.0 before the curve: Reinhard takes it to 0.5
+    // and ACES to 0.80, in linear light, before sRGB encoding.
+    for (tonemap, expected) in [(Tonemap::Reinhard, 188), (Tonemap::Aces, 232)] {
+        let (mut opts, lines) = logged_options();
+        opts.tonemap = Some(tonemap);
+        let (output, _) = run(&rgb16_mcap(), &opts);
+        assert_level(&output, expected);
+        assert!(!lines.lock().unwrap().iter().any(|l| l.contains("truncated")));
+    }
+}
+
+#[test]
+fn preserved_hdr_frames_are_copied_as_is() {
+    let input = rgb16_mcap();
+    let opts = ConvertOptions {
+        preserve_hdr: true,
+        ..ConvertOptions::default()
+    };
+    let (output, stats) = run(&input, &opts);
+    assert_eq!((stats.video_frames, stats.passed_through_messages), (0, 4));
+    let output = read_mcap(&output);
+    let kept: Vec<_> = on_topic(&output, "/cam/image").map(|m| m.data.clone()).collect();
+    let source: Vec<_> = read_mcap(&input).into_iter().map(|m| m.data).collect();
+    assert_eq!(kept, source);
+}
+
+#[test]
+fn preserve_hdr_and_tonemap_are_exclusive() {
+    let opts = ConvertOptions {
+        preserve_hdr: true,
+        tonemap: Some(Tonemap::Reinhard),
+        ..ConvertOptions::default()
+    };
+    let mut output = std::io::Cursor::new(Vec::new());
+    let error = mcap_videoify::convert(&rgb16_mcap(), &mut output, &opts).unwrap_err();
+    assert!(error.to_string().contains("mutually exclusive"), "{:#}", error);
+}
@@ -0,0 +1,76 @@
+//! `strip_indexes`, behind `--message-indexes` and `--chunk-indexes`.
+
+mod common;
+
+use common::*;
+use mcap_videoify::{strip_indexes, ConvertOptions, IndexOptions};
+
+/// A converted output spread over several chunks, with thumbnail attachments.
+fn converted() -> Vec<u8> {
+    let images: Vec<_> = (0..40).map(|i| test_image(64, 48, i)).collect();
+    let opts = ConvertOptions {
+        write_options: mcap::WriteOptions::new().chunk_size(Some(2000)),
+        keyframe_interval: 10,
+        embed_keyframe_thumbs: true,
+        ..ConvertOptions::default()
+    };
+    run(&jpeg_mcap("/cam/image", &images), &opts).0
+}
+
+fn stripped(mcap: &[u8], message_indexes: bool, chunk_indexes: bool) -> Vec<u8> {
+    let mut out = Vec::new();
+    let keep = IndexOptions {
+        message_indexes,
+        chunk_indexes,
+    };
+    strip_indexes(mcap, &mut out, keep).unwrap();
+    out
+}
+
+#[test]
+fn keeping_every_index_leaves_the_file_unchanged() {
+    let mcap = converted();
+    assert_eq!(stripped(&mcap, true, true), mcap);
+}
+
+#[test]
+fn dropped_indexes_leave_the_messages_and_attachments_readable() {
+    let mcap = converted();
+    let original = read_mcap(&mcap);
+    for (message_indexes, chunk_indexes) in [(false, true), (true, false), (false, false)] {
+        let out = stripped(&mcap, message_indexes, chunk_indexes);
+        assert!(out.len() < mcap.len());
+
+        let messages = read_mcap(&out);
+        assert_eq!(messages.len(), original.len());
+        for (a, b) in messages.iter().zip(&original) {
+            assert_eq!((&a.topic, a.sequence, a.log_time, &a.data), (&b.topic, b.sequence, b.log_time, &b.data));
+        }
+
+        let summary = mcap::Summary::read(&out).unwrap().unwrap();
+        assert_eq!(summary.chunk_indexes.is_empty(), !chunk_indexes);
+        assert!(summary.chunk_indexes.iter().all(|c| c.message_index_offsets.is_empty()));
+        assert_eq!(summary.attachment_indexes.len(), 4);
+        for index in &summary.attachment_indexes {
+            let attachment = mcap::read::attachment(&out, index).unwrap();
+            assert_eq!(attachment.media_type, "image/jpeg");
+        }
+    }
+}
+
+#[test]
+fn chunk_indexes_follow_the_moved_chunks() {
+    let mcap = converted();
+    // Dropping the message indexes moves every chunk after the first.
+    let out = stripped(&mcap, false, true);
+    let summary = mcap::Summary::read(&out).unwrap().unwrap();
+    let original = mcap::Summary::read(&mcap).unwrap().unwrap();
+    assert_eq!(summary.chunk_indexes.len(), original.chunk_indexes.len());
+    for (chunk, before) in summary.chunk_indexes.iter().zip(&original.chunk_indexes) {
+        assert!(chunk.chunk_start_offset <= before.chunk_start_offset);
+        assert_eq!(out[chunk.chunk_start_offset as usize], 0x06);
+        let messages = summary.stream_chunk(&out, chunk).unwrap().count();
+        let expected = original.stream_chunk(&mcap, before).unwrap().count();
+        assert_eq!(messages, expected);
+    }
+}
@@ -0,0 +1,63 @@
+//! Keyframe placement under `--keyframe-interval` and `--max-gop-bytes`.
+
+mod common;
+
+use common::*;
+use image::DynamicImage;
+use mcap_videoify::ConvertOptions;
+
+/// Whether each of 40 changing 64x48 frames became a keyframe, with its size.
+fn frames(keyframe_interval: u64, max_gop_bytes: Option<usize>) -> Vec<(bool, usize)> {
+    let images: Vec<_> = (0..40).map(|i| DynamicImage::ImageRgb8(test_image(64, 48, i))).collect();
+    let opts = ConvertOptions {
+        keyframe_interval,
+        max_gop_bytes,
+        ..ConvertOptions::default()
+    };
+    let (output, _) = run(&png_mcap("/cam/image", &images), &opts);
+    let messages = read_mcap(&output);
+    on_topic(&messages, "/cam/image_video")
+        .map(|m| (is_keyframe(&m.data), video_data(&m.data).len()))
+        .collect()
+}
+
+/// Where the keyframes should fall given the frame sizes the encoder produced:
+/// `interval` frames after the previous keyframe, or once the frames after it
+/// reach `max_gop_bytes`.
+fn expected_keyframes(frames: &[(bool, usize)], interval: u64, max_gop_bytes: usize) -> Vec<bool> {
+    let mut since = 0;
+    let mut bytes = 0;
+    let mut expected = Vec::new();
+    for &(keyframe, size) in frames {
+        expected.push(expected.is_empty() || since >= interval || bytes >= max_gop_bytes);
+        if keyframe {
+            (since, bytes) = (0, 0);
+        } else {
+            bytes += size;
+        }
+        since += 1;
+    }
+    expected
+}
+
+#[test]
+fn keyframes_larger_than_the_cap_are_followed_by_p_frames() {
+    let frames = frames(0, Some(200));
+    assert!(frames[0].0 && frames[0].1 > 200, "{:?}", frames[0]);
+    assert!(!frames[1].0);
+    let keyframes: Vec<_> = frames.iter().map(|f| f.0).collect();
+    assert!(keyframes.iter().filter(|&&k| !k).count() >= 20, "{:?}", keyframes);
+    assert_eq!(keyframes, expected_keyframes(&frames, u64::MAX, 200));
+}
+
+#[test]
+fn the_interval_restarts_at_a_byte_cap_keyframe() {
+    // The cap cuts the first group short at frame 4. Later groups, with smaller
+    // P-frames, end at the interval counted from there instead of at frame 6.
+    let (interval, cap) = (6, 300);
+    let frames = frames(interval, Some(cap));
+    let keyframes: Vec<_> = frames.iter().map(|f| f.0).collect();
+    assert_eq!(keyframes, expected_keyframes(&frames, interval, cap));
+    let starts: Vec<_> = (0..keyframes.len()).filter(|&i| keyframes[i]).collect();
+    assert_eq!(starts[..3], [0, 4, 10]);
+}
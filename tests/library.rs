@@ -0,0 +1,21 @@
+//! The library entry point, fed the way the command line feeds it.
+
+mod common;
+
+use common::*;
+use memmap::Mmap;
+use std::fs::{self, File};
+
+#[test]
+fn converts_a_memory_mapped_input_in_place() {
+    let images: Vec<_> = (0..5).map(|i| test_image(32, 32, i)).collect();
+    let path = temp_path("library.mcap");
+    fs::write(&path, jpeg_mcap("/cam/image", &images)).unwrap();
+    let mapped = unsafe { Mmap::map(&File::open(&path).unwrap()) }.unwrap();
+
+    let (output, stats) = run(&mapped, &mcap_videoify::ConvertOptions::default());
+    drop(mapped);
+    fs::remove_file(&path).unwrap();
+    assert_eq!(stats.transcoded_messages, 5);
+    assert_eq!(on_topic(&read_mcap(&output), "/cam/image_video").count(), 5);
+}
@@ -0,0 +1,106 @@
+//! Memory use on long streams: what the converter holds must not grow with
+//! the number of messages.
+
+mod common;
+
+use common::*;
+use memmap::Mmap;
+use std::fs::{self, File};
+use std::sync::{Arc, Mutex};
+
+/// Resident anonymous memory of this process, in bytes. Unlike the peak
+/// (`VmHWM`), this leaves out the memory-mapped input.
+fn rss_anon() -> u64 {
+    let status = fs::read_to_string("/proc/self/status").unwrap();
+    let line = status.lines().find(|l| l.starts_with("RssAnon:")).unwrap();
+    line.split_whitespace().nth(1).unwrap().parse::<u64>().unwrap() * 1024
+}
+
+/// A 160x120 JPEG of noise, whose thumbnail doesn't compress: about 25 kB.
+fn noise_jpeg(seed: u32) -> Vec<u8> {
+    let mut state = seed;
+    jpeg(&image::RgbImage::from_fn(160, 120, |_, _| {
+        state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+        let [_, r, g, b] = state.to_le_bytes();
+        image::Rgb([r, g, b])
+    }))
+}
+
+/// `frames` rounds of one noise frame on each of three topics.
+fn noise_mcap(frames: usize) -> Vec<u8> {
+    let images: Vec<_> = (0..4).map(noise_jpeg).collect();
+    let channels: Vec<_> = (0..3)
+        .map(|t| channel(&format!("/cam{}/image", t), Some(compressed_image_schema()), "protobuf"))
+        .collect();
+    let mut messages = Vec::new();
+    for i in 0..frames {
+        for channel in &channels {
+            let stamp = START_NS + messages.len() as u64 * FRAME_NS;
+            let data = &images[(i + messages.len()) % images.len()];
+            messages.push((channel.clone(), compressed_image(stamp, "cam", "jpeg", data)));
+        }
+    }
+    write_mcap(&messages)
+}
+
+/// Converts `frames` frames on each of three topics, with a keyframe and a
+/// thumbnail for every frame, from a mapped file to a file. Returns by how
+/// much resident memory rose above where it started, sampled at every log line.
+fn memory_growth(frames: usize) -> u64 {
+    let input = temp_path(&format!("long-{}.mcap", frames));
+    let output = temp_path(&format!("long-{}-out.mcap", frames));
+    fs::write(&input, noise_mcap(frames)).unwrap();
+    let mapped = unsafe { Mmap::map(&File::open(&input).unwrap()) }.unwrap();
+
+    let start = rss_anon();
+    let peak = Arc::new(Mutex::new(start));
+    let sampled = peak.clone();
+    let opts = mcap_videoify::ConvertOptions {
+        keyframe_interval: 1,
+        embed_keyframe_thumbs: true,
+        log: Some(Box::new(move |_, _| {
+            let mut peak = sampled.lock().unwrap();
+            *peak = (*peak).max(rss_anon());
+        })),
+        ..mcap_videoify::ConvertOptions::default()
+    };
+    let stats = mcap_videoify::convert(&mapped, File::create(&output).unwrap(), &opts).unwrap();
+    assert_eq!(stats.video_frames, 3 * frames as u64);
+
+    drop(mapped);
+    fs::remove_file(&input).unwrap();
+    fs::remove_file(&output).unwrap();
+    let peak = *peak.lock().unwrap();
+    peak - start
+}
+
+/// Set in the child processes [`memory_stays_flat_as_the_stream_grows`] runs
+/// itself in, so each conversion starts from a fresh heap.
+const FRAMES_VAR: &str = "MCAP_VIDEOIFY_LONG_STREAM_FRAMES";
+
+/// Runs [`memory_growth`] for `frames` in a child process.
+fn memory_growth_in_child(frames: usize) -> u64 {
+    let child = std::process::Command::new(std::env::current_exe().unwrap())
+        .args(["--exact", "memory_stays_flat_as_the_stream_grows", "--nocapture"])
+        .env(FRAMES_VAR, frames.to_string())
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&child.stdout);
+    assert!(child.status.success(), "{}{}", stdout, String::from_utf8_lossy(&child.stderr));
+    // libtest prints the test's name on the same line, ahead of the output.
+    let growth = stdout.lines().find_map(|l| l.split("memory growth: ").nth(1)).unwrap();
+    growth.trim().parse().unwrap()
+}
+
+#[test]
+fn memory_stays_flat_as_the_stream_grows() {
+    if let Ok(frames) = std::env::var(FRAMES_VAR) {
+        println!("memory growth: {}", memory_growth(frames.parse().unwrap()));
+        return;
+    }
+    // Both runs have more thumbnails than the 4 MiB the converter holds back
+    // (6 MB and 24 MB), so held thumbnails would show as 18 MB more.
+    let short = memory_growth_in_child(80);
+    let long = memory_growth_in_child(320);
+    assert!(long < short + (4 << 20), "{} bytes for 240 frames, {} for 960", short, long);
+}
@@ -0,0 +1,61 @@
+//! End-to-end conversions through `--codec raw`, which needs `--features raw-codec`.
+#![cfg(feature = "raw-codec")]
+
+mod common;
+
+use common::*;
+use image::DynamicImage;
+
+#[test]
+fn every_image_becomes_one_frame_with_its_times() {
+    let images: Vec<_> = (0..12).map(|i| test_image(40, 30, i)).collect();
+    let input = png_mcap("/cam/image", &images.iter().cloned().map(DynamicImage::ImageRgb8).collect::<Vec<_>>());
+    let (output, stats) = run(&input, &raw_codec());
+
+    assert_eq!(stats.transcoded_messages, 12);
+    assert_eq!(stats.video_frames, 12);
+    let messages = read_mcap(&output);
+    let video: Vec<_> = on_topic(&messages, "/cam/image_video").collect();
+    assert_eq!(video.len(), images.len());
+    for (i, (message, source)) in video.iter().zip(&images).enumerate() {
+        let time = START_NS + i as u64 * FRAME_NS;
+        assert_eq!(message.schema.as_deref(), Some("foxglove.CompressedVideo"));
+        assert_eq!(message.sequence, i as u32);
+        assert_eq!(message.log_time, time);
+        assert_eq!(message.publish_time, time + PUBLISH_DELAY_NS);
+        assert_eq!(stamp_ns(&message.data), time);
+        assert_eq!(video_format(&message.data), "rgb8");
+        assert_eq!(&raw_frame(&message.data), source);
+    }
+}
+
+#[test]
+fn other_topics_are_copied_in_place() {
+    let images = channel("/cam/image", Some(compressed_image_schema()), "protobuf");
+    let other = channel("/tf", None, "json");
+    let frame = png(DynamicImage::ImageRgb8(test_image(16, 16, 0)));
+    let mut messages = Vec::new();
+    for i in 0..4 {
+        let stamp = START_NS + 2 * i * FRAME_NS;
+        messages.push((images.clone(), compressed_image(stamp, "cam", "png", &frame)));
+        messages.push((other.clone(), format!("{{\"i\":{}}}", i).into_bytes()));
+    }
+    let (output, stats) = run(&write_mcap(&messages), &raw_codec());
+
+    assert_eq!(stats.passed_through_messages, 4);
+    let output = read_mcap(&output);
+    let topics: Vec<_> = output.iter().map(|m| m.topic.as_str()).collect();
+    assert_eq!(topics, ["/cam/image_video", "/tf"].repeat(4));
+    for (i, message) in output.iter().enumerate() {
+        assert_eq!(message.sequence, i as u32);
+        assert_eq!(message.log_time, START_NS + i as u64 * FRAME_NS);
+    }
+    assert_eq!(output[3].data, b"{\"i\":1}");
+}
+
+#[test]
+fn output_is_reproducible() {
+    let images: Vec<_> = (0..6).map(|i| DynamicImage::ImageRgb8(test_image(24, 24, i))).collect();
+    let input = png_mcap("/cam/image", &images);
+    assert_eq!(run(&input, &raw_codec()).0, run(&input, &raw_codec()).0);
+}
@@ -0,0 +1,225 @@
+//! Resolving the image schema of a channel.
+
+mod common;
+
+use common::*;
+use mcap_videoify::{load_external_schema, ConvertOptions, OnError};
+use protobuf::descriptor::field_descriptor_proto::Type;
+use protobuf::descriptor::FileDescriptorSet;
+use protobuf::reflect::FileDescriptor;
+use protobuf::Message;
+use std::borrow::Cow;
+use std::fs;
+use std::sync::Arc;
+
+/// Five JPEG frames on a channel whose CompressedImage schema is `schema`.
+fn images_with_schema(schema: Vec<u8>) -> Vec<u8> {
+    let channel = channel("/cam/image", Some(common::schema("CompressedImage", schema)), "protobuf");
+    let messages: Vec<_> = (0..5)
+        .map(|i| {
+            let data = jpeg(&test_image(32, 32, i));
+            (channel.clone(), compressed_image(START_NS + u64::from(i) * FRAME_NS, "cam", "jpeg", &data))
+        })
+        .collect();
+    write_mcap(&messages)
+}
+
+/// Options using `set`, written to a file and loaded as `--schema` does.
+fn with_external_schema(name: &str, set: Vec<u8>) -> ConvertOptions {
+    let path = temp_path(name);
+    fs::write(&path, set).unwrap();
+    let external = load_external_schema(path.to_str().unwrap()).unwrap();
+    fs::remove_file(&path).unwrap();
+    ConvertOptions {
+        external_schema: Some(external),
+        ..ConvertOptions::default()
+    }
+}
+
+#[test]
+fn an_external_schema_rescues_channels_without_schema_data() {
+    let input = images_with_schema(Vec::new());
+
+    let (_, stats) = run(&input, &ConvertOptions::default());
+    assert_eq!((stats.video_frames, stats.failed_messages), (0, 5));
+
+    let set = descriptor_set("CompressedImage", COMPRESSED_IMAGE, true);
+    let (output, stats) = run(&input, &with_external_schema("external.pb", set));
+    assert_eq!(stats.video_frames, 5);
+    let video: Vec<_> = on_topic(&read_mcap(&output), "/cam/image_video").map(|m| stamp_ns(&m.data)).collect();
+    assert_eq!(video, (0..5).map(|i| START_NS + i * FRAME_NS).collect::<Vec<_>>());
+}
+
+#[test]
+fn the_embedded_schema_wins_over_an_external_one() {
+    // An external definition that puts data and format on other field numbers.
+    let wrong = &[
+        Field("timestamp", 1, Type::TYPE_MESSAGE),
+        Field("data", 7, Type::TYPE_BYTES),
+        Field("format", 8, Type::TYPE_STRING),
+    ];
+    let input = images_with_schema(descriptor_set("CompressedImage", COMPRESSED_IMAGE, true));
+    let opts = with_external_schema("wrong.pb", descriptor_set("CompressedImage", wrong, true));
+    let (_, stats) = run(&input, &opts);
+    assert_eq!((stats.video_frames, stats.failed_messages), (5, 0));
+}
+
+/// Two pass-through topics sharing a schema that is protobuf's own
+/// descriptor.proto, next to an image topic.
+fn pass_through_mcap() -> (Vec<u8>, Arc<mcap::Schema<'static>>) {
+    let mut set = FileDescriptorSet::new();
+    set.file.push(protobuf::descriptor::file_descriptor().proto().clone());
+    let schema = Arc::new(mcap::Schema {
+        name: "google.protobuf.FileDescriptorSet".to_string(),
+        encoding: "protobuf".to_string(),
+        data: Cow::from(set.write_to_bytes().unwrap()),
+    });
+    let payload = set.write_to_bytes().unwrap();
+    let images = channel("/cam/image", Some(compressed_image_schema()), "protobuf");
+    let first = channel("/schemas/a", Some(schema.clone()), "protobuf");
+    let second = channel("/schemas/b", Some(schema.clone()), "protobuf");
+    let frame = jpeg(&test_image(16, 16, 0));
+    let input = write_mcap(&[
+        (images, compressed_image(START_NS, "cam", "jpeg", &frame)),
+        (first, payload.clone()),
+        (second, payload),
+    ]);
+    (input, schema)
+}
+
+#[test]
+fn pass_through_topics_keep_their_schema() {
+    let (input, schema) = pass_through_mcap();
+    let (output, _) = run(&input, &ConvertOptions::default());
+
+    let summary = mcap::Summary::read(&output).unwrap().unwrap();
+    let copies: Vec<_> = summary.schemas.values().filter(|s| s.name == schema.name).collect();
+    assert_eq!(copies.len(), 1);
+    assert_eq!(copies[0].encoding, schema.encoding);
+    assert_eq!(copies[0].data, schema.data);
+
+    let set = FileDescriptorSet::parse_from_bytes(&copies[0].data).unwrap();
+    let files = FileDescriptor::new_dynamic_fds(set.file, &[]).unwrap();
+    let descriptor = files
+        .iter()
+        .find_map(|f| f.message_by_full_name(".google.protobuf.FileDescriptorSet"))
+        .unwrap();
+    let messages = read_mcap(&output);
+    for topic in ["/schemas/a", "/schemas/b"] {
+        let message = on_topic(&messages, topic).next().unwrap();
+        assert_eq!(message.schema.as_deref(), Some("google.protobuf.FileDescriptorSet"));
+        let parsed = descriptor.parse_from_bytes(&message.data).unwrap();
+        let file = descriptor.field_by_name("file").unwrap();
+        assert_eq!(file.get_repeated(&*parsed).len(), 1);
+    }
+}
+
+#[test]
+fn schema_records_can_be_left_out() {
+    let (input, _) = pass_through_mcap();
+    let opts = ConvertOptions {
+        copy_schema_records: false,
+        ..ConvertOptions::default()
+    };
+    let (output, _) = run(&input, &opts);
+    let messages = read_mcap(&output);
+    for topic in ["/schemas/a", "/schemas/b"] {
+        let message = on_topic(&messages, topic).next().unwrap();
+        assert_eq!(message.schema, None);
+        assert_eq!(message.data, read_mcap(&input)[1].data);
+    }
+    assert_eq!(on_topic(&messages, "/cam/image_video").next().unwrap().schema.as_deref(), Some("foxglove.CompressedVideo"));
+}
+
+#[test]
+fn a_schema_without_timestamp_takes_log_time() {
+    let minimal = &[Field("data", 2, Type::TYPE_BYTES), Field("format", 3, Type::TYPE_STRING)];
+    let schema = common::schema("CompressedImage", descriptor_set("CompressedImage", minimal, true));
+    let channel = channel("/cam/image", Some(schema), "protobuf");
+    let messages: Vec<_> = (0..4)
+        .map(|i| {
+            let data = jpeg(&test_image(32, 32, i));
+            (channel.clone(), encode(&[(2, Value::Bytes(&data)), (3, Value::Bytes(b"jpeg"))]))
+        })
+        .collect();
+    let (output, stats) = run(&write_mcap(&messages), &ConvertOptions::default());
+
+    assert_eq!(stats.video_frames, 4);
+    let output = read_mcap(&output);
+    let video: Vec<_> = on_topic(&output, "/cam/image_video").collect();
+    assert_eq!(video.len(), 4);
+    for message in video {
+        assert_eq!(stamp_ns(&message.data), message.log_time);
+        assert_eq!(field(&message.data, 2), None);
+    }
+}
+
+#[test]
+fn two_schema_versions_convert_side_by_side() {
+    // A later version that renumbers data and format and lists its own file first.
+    let renumbered = &[
+        Field("timestamp", 1, Type::TYPE_MESSAGE),
+        Field("frame_id", 4, Type::TYPE_STRING),
+        Field("data", 5, Type::TYPE_BYTES),
+        Field("format", 6, Type::TYPE_STRING),
+    ];
+    let old = channel("/cam/a", Some(compressed_image_schema()), "protobuf");
+    let new = common::schema("CompressedImage", descriptor_set("CompressedImage", renumbered, false));
+    let new = channel("/cam/b", Some(new), "protobuf");
+    let images: Vec<_> = (0..8).map(|i| test_image(32, 32, i * 10)).collect();
+    let mut messages = Vec::new();
+    for (i, img) in images.iter().enumerate() {
+        let stamp = START_NS + i as u64 * FRAME_NS;
+        let data = jpeg(img);
+        if i % 2 == 0 {
+            messages.push((old.clone(), compressed_image(stamp, "cam", "jpeg", &data)));
+        } else {
+            let payload = encode(&[(1, Value::Timestamp(stamp)), (5, Value::Bytes(&data)), (6, Value::Bytes(b"jpeg"))]);
+            messages.push((new.clone(), payload));
+        }
+    }
+    let (output, stats) = run(&write_mcap(&messages), &ConvertOptions::default());
+
+    assert_eq!((stats.video_frames, stats.failed_messages), (8, 0));
+    let output = read_mcap(&output);
+    for (topic, first) in [("/cam/a_video", 0), ("/cam/b_video", 1)] {
+        let video: Vec<_> = on_topic(&output, topic).collect();
+        let stamps: Vec<_> = video.iter().map(|m| stamp_ns(&m.data)).collect();
+        let expected: Vec<_> = (first..8).step_by(2).map(|i| START_NS + i as u64 * FRAME_NS).collect();
+        assert_eq!(stamps, expected, "{}", topic);
+        let decoded = decode_h264(video.into_iter());
+        for (frame, source) in decoded.iter().zip(images.iter().skip(first).step_by(2)) {
+            assert!(mean_abs_diff(frame, source) < 24.0, "{}", topic);
+        }
+        assert_eq!(decoded.len(), 4, "{}", topic);
+    }
+}
+
+#[test]
+fn the_message_is_found_in_any_file_of_the_set() {
+    let reordered = descriptor_set("CompressedImage", COMPRESSED_IMAGE, false);
+    let (_, stats) = run(&images_with_schema(reordered), &ConvertOptions::default());
+    assert_eq!((stats.video_frames, stats.failed_messages), (5, 0));
+}
+
+#[test]
+fn unusable_embedded_schemas_name_the_topic() {
+    let fail = ConvertOptions {
+        on_error: OnError::Fail,
+        ..ConvertOptions::default()
+    };
+    let cases = [
+        // A set that defines some other message.
+        (descriptor_set("Image", COMPRESSED_IMAGE, true), "No foxglove.CompressedImage schema for topic /cam/image"),
+        (b"\xff\xff\xff".to_vec(), "Couldn't parse the embedded schema foxglove.CompressedImage of topic /cam/image"),
+    ];
+    for (schema, message) in cases {
+        let input = images_with_schema(schema);
+        let (_, stats) = run(&input, &ConvertOptions::default());
+        assert_eq!((stats.video_frames, stats.failed_messages), (0, 5));
+
+        let mut output = std::io::Cursor::new(Vec::new());
+        let error = format!("{:#}", mcap_videoify::convert(&input, &mut output, &fail).unwrap_err());
+        assert!(error.contains(message), "{}", error);
+    }
+}
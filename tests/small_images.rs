@@ -0,0 +1,76 @@
+//! Frames under 16x16 or odd-sized, which openh264 can't encode as they are.
+
+mod common;
+
+use common::*;
+use image::{DynamicImage, RgbImage};
+use mcap_videoify::ConvertOptions;
+
+fn rgb(images: &[RgbImage]) -> Vec<DynamicImage> {
+    images.iter().cloned().map(DynamicImage::ImageRgb8).collect()
+}
+
+/// Converts five frames of `width` x `height` and decodes the video back.
+fn round_trip(width: u32, height: u32) -> (Vec<RgbImage>, Vec<RgbImage>) {
+    let images: Vec<_> = (0..5).map(|i| test_image(width, height, i * 10)).collect();
+    let (output, _) = run(&png_mcap("/cam/image", &rgb(&images)), &ConvertOptions::default());
+    let messages = read_mcap(&output);
+    (images, decode_h264(on_topic(&messages, "/cam/image_video")))
+}
+
+#[test]
+fn padding_is_cropped_off_small_frames() {
+    for (width, height) in [(10, 12), (6, 4), (16, 4)] {
+        let (images, decoded) = round_trip(width, height);
+        assert_eq!(decoded.len(), images.len());
+        for (source, frame) in images.iter().zip(&decoded) {
+            assert_eq!(frame.dimensions(), (width, height));
+            assert!(mean_abs_diff(source, frame) < 24.0, "{}x{}", width, height);
+        }
+    }
+}
+
+#[test]
+fn odd_sizes_keep_one_padded_line() {
+    for (width, height) in [(33, 31), (9, 7)] {
+        let (images, decoded) = round_trip(width, height);
+        assert_eq!(decoded.len(), images.len());
+        for (source, frame) in images.iter().zip(&decoded) {
+            assert_eq!(frame.dimensions(), (width + 1, height + 1));
+            let picture = image::imageops::crop_imm(frame, 0, 0, width, height).to_image();
+            assert!(mean_abs_diff(source, &picture) < 24.0, "{}x{}", width, height);
+        }
+    }
+}
+
+#[test]
+fn frame_meta_reports_the_displayed_size() {
+    let images: Vec<_> = (0..2).map(|i| test_image(9, 12, i)).collect();
+    let opts = ConvertOptions {
+        emit_frame_meta: true,
+        ..ConvertOptions::default()
+    };
+    let (output, _) = run(&png_mcap("/cam/image", &rgb(&images)), &opts);
+    let messages = read_mcap(&output);
+    for meta in on_topic(&messages, "/cam/image_video_meta") {
+        let json = String::from_utf8(meta.data.clone()).unwrap();
+        assert!(json.contains("\"source_width\":9,\"source_height\":12"), "{}", json);
+        assert!(json.contains("\"encoded_width\":10,\"encoded_height\":12"), "{}", json);
+    }
+}
+
+#[test]
+fn small_images_can_be_left_as_is() {
+    let images: Vec<_> = (0..3).map(|i| test_image(8, 8, i)).collect();
+    let input = png_mcap("/cam/image", &rgb(&images));
+    let opts = ConvertOptions {
+        pass_through_small_images: true,
+        ..ConvertOptions::default()
+    };
+    let (output, stats) = run(&input, &opts);
+    assert_eq!(stats.video_frames, 0);
+    let messages = read_mcap(&output);
+    let kept: Vec<_> = on_topic(&messages, "/cam/image").collect();
+    assert_eq!(kept.len(), 3);
+    assert_eq!(kept[0].schema.as_deref(), Some("foxglove.CompressedImage"));
+}
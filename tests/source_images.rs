@@ -0,0 +1,158 @@
+//! Source images that aren't a plain 8-bit picture of a fixed size.
+
+mod common;
+
+use common::*;
+use image::{Delay, DynamicImage, Frame, RgbaImage};
+use mcap_videoify::{ConvertOptions, FormatChange, OnError};
+
+/// A GIF of three 100 ms frames.
+fn animated_gif() -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut encoder = image::codecs::gif::GifEncoder::new(&mut data);
+    for k in 0..3u32 {
+        let rgba = RgbaImage::from_fn(32, 24, |x, y| image::Rgba([(x * 8 + k * 60) as u8, (y * 10) as u8, 0, 255]));
+        encoder
+            .encode_frame(Frame::from_parts(rgba, 0, 0, Delay::from_numer_denom_ms(100, 1)))
+            .unwrap();
+    }
+    drop(encoder);
+    data
+}
+
+/// An animated GIF message between two JPEG ones.
+fn animation_mcap() -> Vec<u8> {
+    let channel = channel("/cam/image", Some(compressed_image_schema()), "protobuf");
+    let still = jpeg(&test_image(32, 24, 0));
+    write_mcap(&[
+        (channel.clone(), compressed_image(START_NS, "cam", "jpeg", &still)),
+        (channel.clone(), compressed_image(START_NS + FRAME_NS, "cam", "gif", &animated_gif())),
+        (channel, compressed_image(START_NS + 2 * FRAME_NS, "cam", "jpeg", &still)),
+    ])
+}
+
+#[test]
+fn animations_are_skipped_by_default() {
+    let (output, stats) = run(&animation_mcap(), &ConvertOptions::default());
+    assert_eq!((stats.video_frames, stats.failed_messages), (2, 1));
+    let sequences: Vec<_> = on_topic(&read_mcap(&output), "/cam/image_video").map(|m| m.sequence).collect();
+    assert_eq!(sequences, [0, 2]);
+}
+
+#[test]
+fn animations_fail_under_on_error_fail() {
+    let opts = ConvertOptions {
+        on_error: OnError::Fail,
+        ..ConvertOptions::default()
+    };
+    let mut output = std::io::Cursor::new(Vec::new());
+    let error = mcap_videoify::convert(&animation_mcap(), &mut output, &opts).unwrap_err();
+    assert!(format!("{:#}", error).contains("animated image with 3 frames"), "{:#}", error);
+}
+
+#[test]
+fn expanded_animations_get_a_frame_per_delay() {
+    let opts = ConvertOptions {
+        expand_animations: true,
+        ..ConvertOptions::default()
+    };
+    let (output, stats) = run(&animation_mcap(), &opts);
+    assert_eq!(stats.video_frames, 5);
+    let messages = read_mcap(&output);
+    let frames: Vec<_> = on_topic(&messages, "/cam/image_video")
+        .map(|m| (m.sequence, m.log_time - START_NS, stamp_ns(&m.data) - START_NS))
+        .collect();
+    let gif = FRAME_NS;
+    assert_eq!(
+        frames,
+        [
+            (0, 0, 0),
+            (1, gif, gif),
+            (1, gif + 100_000_000, gif + 100_000_000),
+            (1, gif + 200_000_000, gif + 200_000_000),
+            (2, 2 * FRAME_NS, 2 * FRAME_NS),
+        ]
+    );
+}
+
+/// Two color frames, two grayscale frames, then color again, on one topic.
+fn format_change_mcap() -> Vec<u8> {
+    let images: Vec<_> = (0..5)
+        .map(|i| match i {
+            2 | 3 => DynamicImage::ImageLuma8(image::imageops::grayscale(&test_image(32, 24, i))),
+            _ => DynamicImage::ImageRgb8(test_image(32, 24, i)),
+        })
+        .collect();
+    png_mcap("/cam/image", &images)
+}
+
+fn with_format_change(on_format_change: FormatChange) -> ConvertOptions {
+    ConvertOptions {
+        on_format_change,
+        ..ConvertOptions::default()
+    }
+}
+
+#[test]
+fn format_changes_restart_at_a_keyframe_by_default() {
+    let (output, stats) = run(&format_change_mcap(), &ConvertOptions::default());
+    assert_eq!(stats.video_frames, 5);
+    let messages = read_mcap(&output);
+    let keyframes: Vec<_> = on_topic(&messages, "/cam/image_video").map(|m| is_keyframe(&m.data)).collect();
+    assert_eq!(keyframes, [true, false, true, false, true]);
+}
+
+#[test]
+fn format_changes_are_dropped_under_skip() {
+    let (output, stats) = run(&format_change_mcap(), &with_format_change(FormatChange::Skip));
+    assert_eq!(stats.video_frames, 3);
+    let messages = read_mcap(&output);
+    let video: Vec<_> = on_topic(&messages, "/cam/image_video").collect();
+    assert_eq!(video.iter().map(|m| m.sequence).collect::<Vec<_>>(), [0, 1, 4]);
+    assert_eq!(video.iter().map(|m| is_keyframe(&m.data)).collect::<Vec<_>>(), [true, false, false]);
+}
+
+#[test]
+fn format_changes_fail_under_error() {
+    let mut output = std::io::Cursor::new(Vec::new());
+    let error = mcap_videoify::convert(&format_change_mcap(), &mut output, &with_format_change(FormatChange::Error)).unwrap_err();
+    let error = format!("{:#}", error);
+    assert!(error.contains("Topic /cam/image changed image format"), "{}", error);
+    assert!(error.contains("at message 2"), "{}", error);
+}
+
+/// Five 64x48 JPEG frames, then five at 32x24, on one topic.
+fn resolution_change_mcap() -> Vec<u8> {
+    let images: Vec<_> = (0..10)
+        .map(|i| match i < 5 {
+            true => test_image(64, 48, i),
+            false => test_image(32, 24, i),
+        })
+        .collect();
+    jpeg_mcap("/cam/image", &images)
+}
+
+#[test]
+fn resolution_changes_restart_at_a_keyframe() {
+    let (output, stats) = run(&resolution_change_mcap(), &ConvertOptions::default());
+    assert_eq!(stats.video_frames, 10);
+    let messages = read_mcap(&output);
+    let video: Vec<_> = on_topic(&messages, "/cam/image_video").collect();
+    let keyframes: Vec<_> = video.iter().map(|m| is_keyframe(&m.data)).collect();
+    assert_eq!(keyframes, [true, false, false, false, false, true, false, false, false, false]);
+    let sizes: Vec<_> = decode_h264(video.into_iter()).iter().map(|f| f.dimensions()).collect();
+    assert_eq!(sizes, [[(64, 48); 5], [(32, 24); 5]].concat());
+}
+
+#[test]
+fn resolution_changes_follow_on_format_change() {
+    let (output, stats) = run(&resolution_change_mcap(), &with_format_change(FormatChange::Skip));
+    assert_eq!(stats.video_frames, 5);
+    let sequences: Vec<_> = on_topic(&read_mcap(&output), "/cam/image_video").map(|m| m.sequence).collect();
+    assert_eq!(sequences, [0, 1, 2, 3, 4]);
+
+    let mut output = std::io::Cursor::new(Vec::new());
+    let error = mcap_videoify::convert(&resolution_change_mcap(), &mut output, &with_format_change(FormatChange::Error)).unwrap_err();
+    let error = format!("{:#}", error);
+    assert!(error.contains("from 64x48 Jpeg Rgb8 to 32x24 Jpeg Rgb8 at message 5"), "{}", error);
+}
@@ -0,0 +1,34 @@
+//! Topics are converted on threads of their own, but the output must not depend
+//! on how those threads are scheduled.
+
+mod common;
+
+use common::*;
+
+#[test]
+fn output_follows_the_input_order_on_every_run() {
+    let input = cameras_mcap(6, 8, 48, 32);
+    let (opts, lines) = logged_options();
+    let (first, stats) = run(&input, &opts);
+    let first_log = lines.lock().unwrap().clone();
+    assert_eq!(stats.video_frames, 48);
+
+    let messages = read_mcap(&first);
+    let expected: Vec<_> = (0..8)
+        .flat_map(|_| (0..6).map(|t| format!("/cam{}/image_video", t)).chain(["/tf".to_string()]))
+        .collect();
+    let topics: Vec<_> = messages.iter().map(|m| m.topic.clone()).collect();
+    assert_eq!(topics, expected);
+    for (i, message) in messages.iter().enumerate() {
+        assert_eq!(message.sequence, i as u32);
+        assert_eq!(message.log_time, START_NS + i as u64 * FRAME_NS);
+        assert_eq!(message.publish_time, message.log_time + PUBLISH_DELAY_NS);
+    }
+
+    for _ in 0..5 {
+        lines.lock().unwrap().clear();
+        let (output, _) = run(&input, &opts);
+        assert!(output == first, "output differs between runs");
+        assert_eq!(*lines.lock().unwrap(), first_log);
+    }
+}
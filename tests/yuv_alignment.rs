@@ -0,0 +1,44 @@
+//! `--yuv-alignment`, which hands the encoder I420 planes with padded strides.
+
+mod common;
+
+use common::*;
+use image::DynamicImage;
+use mcap_videoify::ConvertOptions;
+
+/// Converts frames of 50x30, whose chroma rows of 25 bytes fit no alignment.
+fn convert(yuv_alignment: Option<usize>) -> (Vec<image::RgbImage>, Vec<u8>) {
+    let images: Vec<_> = (0..6).map(|i| test_image(50, 30, i * 10)).collect();
+    let rgb: Vec<_> = images.iter().cloned().map(DynamicImage::ImageRgb8).collect();
+    let opts = ConvertOptions {
+        yuv_alignment,
+        ..ConvertOptions::default()
+    };
+    (images, run(&png_mcap("/cam/image", &rgb), &opts).0)
+}
+
+fn bitstream(output: &[u8]) -> Vec<Vec<u8>> {
+    let messages = read_mcap(output);
+    on_topic(&messages, "/cam/image_video").map(|m| video_data(&m.data).to_vec()).collect()
+}
+
+#[test]
+fn padded_strides_encode_the_same_pictures() {
+    let packed = bitstream(&convert(None).1);
+    assert_eq!(packed.len(), 6);
+    for alignment in [16, 64, 256] {
+        assert_eq!(bitstream(&convert(Some(alignment)).1), packed, "alignment {}", alignment);
+    }
+}
+
+#[test]
+fn padded_strides_decode_to_the_source() {
+    let (images, output) = convert(Some(64));
+    let messages = read_mcap(&output);
+    let decoded = decode_h264(on_topic(&messages, "/cam/image_video"));
+    assert_eq!(decoded.len(), images.len());
+    for (source, frame) in images.iter().zip(&decoded) {
+        assert_eq!(frame.dimensions(), (50, 30));
+        assert!(mean_abs_diff(source, frame) < 24.0);
+    }
+}